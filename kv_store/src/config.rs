@@ -0,0 +1,338 @@
+use crate::lease::LeaseConfig;
+use crate::network::Codec;
+use serde::Deserialize;
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+
+/// The keys of [`Config`], which can be overridden by environment variables and flags.
+const KEYS: &[&str] = &[
+    "pid",
+    "nodes",
+    "configuration_id",
+    "db_path",
+    "storage_path",
+    "listen_addr",
+    "peers",
+    "api_addr",
+    "codec",
+    "msg_interval_ms",
+    "tick_interval_ms",
+    "election_tick_timeout",
+    "snapshot_interval",
+    "lease_duration_ms",
+    "max_clock_drift_ms",
+    "expire_interval_ms",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Id of this server, which cannot be 0 as that is the client API.
+    pub pid: u64,
+    /// The servers of the cluster, including this one.
+    pub nodes: Vec<u64>,
+    /// The configuration that the server is in when it is first started. Greater than 1 for a
+    /// server that is added to a running cluster, whose `nodes` are those of the new
+    /// configuration.
+    pub configuration_id: u32,
+    /// Directory of the database, `db_{pid}` if not given.
+    pub db_path: Option<String>,
+    /// Directory of the OmniPaxos log, `omnipaxos_storage_{pid}` if not given. The logs of later
+    /// configurations are kept next to it, suffixed with `_{configuration_id}`.
+    pub storage_path: Option<String>,
+    /// Address to listen on for the other servers. If given, the servers connect to each other
+    /// directly at the addresses in `peers`, instead of through the network actor.
+    pub listen_addr: Option<String>,
+    /// Addresses of the other servers, used if `listen_addr` is given.
+    pub peers: Vec<Peer>,
+    /// Address to listen on for clients. If not given, the server dials the network actor, which
+    /// serves the clients.
+    pub api_addr: Option<String>,
+    /// How the messages that this server sends are encoded, `bincode` or `json`. The servers and
+    /// clients of a cluster may use different codecs.
+    pub codec: Codec,
+    /// How often messages are exchanged with the network and decided entries applied.
+    pub msg_interval_ms: u64,
+    /// How often OmniPaxos ticks and the leader renews its lease.
+    pub tick_interval_ms: u64,
+    /// Number of ticks without hearing from the leader after which a new one is elected.
+    pub election_tick_timeout: u64,
+    /// The log is compacted into a snapshot whenever the decided index is a multiple of this, or
+    /// never if 0.
+    pub snapshot_interval: u64,
+    /// Must be shorter than the election timeout, see [`LeaseConfig`].
+    pub lease_duration_ms: u64,
+    pub max_clock_drift_ms: u64,
+    /// How often the leader advances the replicated clock that keys with a TTL expire against.
+    pub expire_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Peer {
+    pub pid: u64,
+    pub addr: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pid: 0,
+            nodes: vec![],
+            configuration_id: 1,
+            db_path: None,
+            storage_path: None,
+            listen_addr: None,
+            peers: vec![],
+            api_addr: None,
+            codec: Codec::default(),
+            msg_interval_ms: 1,
+            tick_interval_ms: 10,
+            election_tick_timeout: 5,
+            snapshot_interval: 5,
+            lease_duration_ms: 40,
+            max_clock_drift_ms: 5,
+            expire_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io(String, io::Error),
+    /// The file is not valid TOML, or a value has the wrong type.
+    Parse(String),
+    /// The values are well-formed but do not work together.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path, e),
+            ConfigError::Parse(reason) => write!(f, "{}", reason),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the configuration from the TOML file given with `--config` or `CONFIG`, if any.
+    /// Every key can be overridden by the environment variable of the same name in upper case,
+    /// e.g. `NODES`, and that again by a flag, e.g. `--nodes "[1, 2, 3]"`. Values are written as
+    /// in TOML, except that strings may be given without quotes.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut path = env::var("CONFIG").ok();
+        let mut flags = vec![];
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let key = match arg.strip_prefix("--") {
+                Some(key) => key.replace('-', "_"),
+                None => return Err(ConfigError::Invalid(format!("unexpected argument {}", arg))),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::Invalid(format!("missing value for {}", arg)))?;
+            if key == "config" {
+                path = Some(value);
+            } else if KEYS.contains(&key.as_str()) {
+                flags.push((key, value));
+            } else {
+                return Err(ConfigError::Invalid(format!("unknown flag {}", arg)));
+            }
+        }
+        let mut table = match path {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(format!("{}: {}", path, e)))?
+            }
+            None => toml::value::Table::new(),
+        };
+        for key in KEYS {
+            if let Ok(value) = env::var(key.to_uppercase()) {
+                table.insert(key.to_string(), parse_value(&value));
+            }
+        }
+        for (key, value) in flags {
+            table.insert(key, parse_value(&value));
+        }
+        let config: Self = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_string()));
+        if self.pid == 0 {
+            return invalid("pid is missing or 0");
+        }
+        if !self.nodes.contains(&self.pid) {
+            return invalid("nodes must include pid");
+        }
+        if self.nodes.contains(&0) {
+            return invalid("nodes cannot include 0");
+        }
+        if (1..self.nodes.len()).any(|i| self.nodes[i..].contains(&self.nodes[i - 1])) {
+            return invalid("nodes must be distinct");
+        }
+        if self.listen_addr.is_some() {
+            let has_addr = |pid: &u64| self.peers.iter().any(|peer| peer.pid == *pid);
+            if let Some(pid) = self
+                .nodes
+                .iter()
+                .find(|pid| **pid != self.pid && !has_addr(pid))
+            {
+                let reason = format!("peers has no address for node {}", pid);
+                return Err(ConfigError::Invalid(reason));
+            }
+        }
+        if self.configuration_id == 0 {
+            return invalid("configuration_id starts at 1");
+        }
+        if self.msg_interval_ms == 0 || self.tick_interval_ms == 0 || self.expire_interval_ms == 0 {
+            return invalid("intervals must be positive");
+        }
+        if self.lease_duration_ms >= self.election_tick_timeout * self.tick_interval_ms {
+            return invalid("lease_duration_ms must be shorter than the election timeout");
+        }
+        if self.max_clock_drift_ms >= self.lease_duration_ms {
+            return invalid("max_clock_drift_ms must be shorter than lease_duration_ms");
+        }
+        Ok(())
+    }
+
+    pub fn db_path(&self) -> String {
+        self.db_path
+            .clone()
+            .unwrap_or_else(|| format!("db_{}", self.pid))
+    }
+
+    /// Returns the directory of the OmniPaxos log of a configuration. The first configuration
+    /// keeps the directory it had before there were others.
+    pub fn storage_path(&self, configuration_id: u32) -> String {
+        let path = self
+            .storage_path
+            .clone()
+            .unwrap_or_else(|| format!("omnipaxos_storage_{}", self.pid));
+        match configuration_id {
+            1 => path,
+            id => format!("{}_{}", path, id),
+        }
+    }
+
+    pub fn lease_config(&self) -> LeaseConfig {
+        LeaseConfig {
+            duration: Duration::from_millis(self.lease_duration_ms),
+            max_clock_drift: Duration::from_millis(self.max_clock_drift_ms),
+        }
+    }
+}
+
+/// Parses an overridden value as a TOML value, or as a string if it is not one.
+fn parse_value(value: &str) -> toml::Value {
+    match toml::from_str::<toml::value::Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table
+            .remove("value")
+            .unwrap_or(toml::Value::String(value.to_string())),
+        Err(_) => toml::Value::String(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pid: u64, nodes: &[u64]) -> Config {
+        Config {
+            pid,
+            nodes: nodes.to_vec(),
+            ..Config::default()
+        }
+    }
+
+    fn reason(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(reason)) => reason,
+            result => panic!("not invalid: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn values_are_parsed_as_toml() {
+        assert_eq!(parse_value("30"), toml::Value::Integer(30));
+        let nodes = parse_value("[1, 2, 3]");
+        assert_eq!(nodes.as_array().unwrap().len(), 3);
+        assert_eq!(
+            parse_value("\"s1:9000\""),
+            toml::Value::String("s1:9000".to_string())
+        );
+        let peers = parse_value("[{ pid = 2, addr = \"s2:9000\" }]");
+        assert_eq!(peers[0]["addr"].as_str(), Some("s2:9000"));
+    }
+
+    #[test]
+    fn strings_may_be_given_without_quotes() {
+        assert_eq!(parse_value("json"), toml::Value::String("json".to_string()));
+        assert_eq!(
+            parse_value("0.0.0.0:9000"),
+            toml::Value::String("0.0.0.0:9000".to_string())
+        );
+        assert_eq!(parse_value(""), toml::Value::String(String::new()));
+    }
+
+    #[test]
+    fn valid_config() {
+        assert!(config(1, &[1, 2, 3]).validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_nodes() {
+        assert_eq!(reason(&config(0, &[1, 2, 3])), "pid is missing or 0");
+        assert_eq!(reason(&config(4, &[1, 2, 3])), "nodes must include pid");
+        assert_eq!(reason(&config(1, &[0, 1, 2])), "nodes cannot include 0");
+        assert_eq!(reason(&config(1, &[1, 2, 1])), "nodes must be distinct");
+    }
+
+    #[test]
+    fn direct_connections_need_the_addresses_of_all_peers() {
+        let mut config = config(1, &[1, 2, 3]);
+        config.listen_addr = Some("0.0.0.0:9000".to_string());
+        config.peers = vec![Peer {
+            pid: 2,
+            addr: "s2:9000".to_string(),
+        }];
+        assert_eq!(reason(&config), "peers has no address for node 3");
+        config.peers.push(Peer {
+            pid: 3,
+            addr: "s3:9000".to_string(),
+        });
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn lease_must_be_shorter_than_the_election_timeout() {
+        let mut config = config(1, &[1, 2, 3]);
+        config.lease_duration_ms = config.election_tick_timeout * config.tick_interval_ms;
+        let expected = "lease_duration_ms must be shorter than the election timeout";
+        assert_eq!(reason(&config), expected);
+        config.lease_duration_ms = config.max_clock_drift_ms;
+        let expected = "max_clock_drift_ms must be shorter than lease_duration_ms";
+        assert_eq!(reason(&config), expected);
+    }
+
+    #[test]
+    fn intervals_must_be_positive() {
+        let mut config = config(1, &[1, 2, 3]);
+        config.configuration_id = 0;
+        assert_eq!(reason(&config), "configuration_id starts at 1");
+        config.configuration_id = 1;
+        config.expire_interval_ms = 0;
+        assert_eq!(reason(&config), "intervals must be positive");
+    }
+}
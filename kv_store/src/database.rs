@@ -1,54 +1,1235 @@
-use crate::kv::{KVCommand, KeyValue};
-use rocksdb::{Options, DB};
+use crate::kv::{
+    check_namespace_name, no_such_namespace, ClientId, ClientSession, Command, CommandResult,
+    Compaction, Configuration, KVCommand, KVSnapshot, Key, KeyValue, Namespace, NamespaceSnapshot,
+    VersionedValue, DEFAULT_NAMESPACE,
+};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle, Direction, ErrorKind, IteratorMode,
+    Options, WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Column family holding replica metadata, kept apart from the user keys.
+const META_CF: &str = "meta";
+/// Column family holding the [`ClientSession`] of every client, keyed by client id.
+const SESSIONS_CF: &str = "sessions";
+/// Column family holding the version of every key, the index of the log entry that last wrote it.
+const VERSIONS_CF: &str = "versions";
+/// Column family holding the time of the replicated clock at which a key expires, for the keys
+/// written with a TTL.
+const EXPIRY_CF: &str = "expiry";
+/// Column family holding the [`Compaction`] of every namespace other than the default one, keyed
+/// by name. A namespace exists once it is in here.
+const NAMESPACES_CF: &str = "namespaces";
+/// Key under which the index of the last applied log entry is stored in [`META_CF`].
+const APPLIED_IDX_KEY: &[u8] = b"applied_idx";
+/// Key under which the replicated clock is stored in [`META_CF`].
+const CLOCK_KEY: &[u8] = b"clock";
+/// Key under which the [`Configuration`] that the replica moved to last is stored in [`META_CF`].
+const CONFIGURATION_KEY: &[u8] = b"configuration";
+/// Key under which the number of chunks of the base state is stored in [`META_CF`]. The base
+/// state is the state that the configuration the replica moved to last started from, which the
+/// snapshots of its log build on and which is sent to the replicas that join it.
+const BASE_CHUNKS_KEY: &[u8] = b"base_chunks";
+/// Prefix of the keys under which the chunks of the base state are stored in [`META_CF`], followed
+/// by the index of the chunk.
+const BASE_CHUNK_PREFIX: &[u8] = b"base_chunk:";
+/// Maximum length of a chunk of the base state, which is sent to a joining replica in a message of
+/// its own.
+const BASE_CHUNK_LEN: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// RocksDB failed to read or write.
+    Io(rocksdb::Error),
+    /// The stored data is not in the format it was written in.
+    Corruption(String),
+    /// A session or namespace could not be serialized or deserialized.
+    Encoding(serde_json::Error),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Io(e) => write!(f, "I/O error: {}", e),
+            DatabaseError::Corruption(reason) => write!(f, "corrupt database: {}", reason),
+            DatabaseError::Encoding(e) => write!(f, "encoding error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl From<rocksdb::Error> for DatabaseError {
+    fn from(e: rocksdb::Error) -> Self {
+        match e.kind() {
+            ErrorKind::Corruption => DatabaseError::Corruption(e.into_string()),
+            _ => DatabaseError::Io(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for DatabaseError {
+    fn from(e: serde_json::Error) -> Self {
+        DatabaseError::Encoding(e)
+    }
+}
+
+fn base_chunk_key(idx: u64) -> Vec<u8> {
+    [BASE_CHUNK_PREFIX, &idx.to_be_bytes()].concat()
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64, DatabaseError> {
+    match bytes.try_into() {
+        Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+        Err(_) => Err(DatabaseError::Corruption(format!(
+            "expected 8 bytes, found {}",
+            bytes.len()
+        ))),
+    }
+}
+
+/// The column families of a namespace, holding its keys and their versions and expiry times.
+struct Keyspace<'a> {
+    keys: &'a ColumnFamily,
+    versions: &'a ColumnFamily,
+    expiry: &'a ColumnFamily,
+}
+
+/// Returns the names of the column families of a namespace, in the order of the fields of
+/// [`Keyspace`]. The default namespace keeps the column families it had before there were others.
+fn cf_names(namespace: &str) -> [String; 3] {
+    if namespace == DEFAULT_NAMESPACE {
+        [DEFAULT_COLUMN_FAMILY_NAME, VERSIONS_CF, EXPIRY_CF].map(String::from)
+    } else {
+        ["", ":versions", ":expiry"].map(|suffix| format!("ns:{}{}", namespace, suffix))
+    }
+}
+
+fn cf_options(compaction: Compaction) -> Options {
+    let mut opts = Options::default();
+    opts.set_compaction_style(match compaction {
+        Compaction::Level => DBCompactionStyle::Level,
+        Compaction::Universal => DBCompactionStyle::Universal,
+        Compaction::Fifo => DBCompactionStyle::Fifo,
+    });
+    opts
+}
+
+/// The writes of a log entry, which are not in the database until they are written together.
+/// The commands of a batch see the values and sessions written by those before them.
+#[derive(Default)]
+struct Writes {
+    batch: WriteBatch,
+    values: HashMap<(Namespace, Key), Option<VersionedValue>>,
+    sessions: HashMap<ClientId, ClientSession>,
+    /// The namespaces dropped by the batch, whose column families are dropped once it is written.
+    dropped: Vec<Namespace>,
+}
 
 pub struct Database {
     rocks_db: DB,
 }
 
 impl Database {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &str) -> Result<Self, DatabaseError> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
-        let rocks_db = DB::open(&opts, path).unwrap();
-        Self { rocks_db }
+        opts.create_missing_column_families(true);
+        // every column family has to be opened, including those of the namespaces
+        let mut cfs = DB::list_cf(&opts, path).unwrap_or_default();
+        for cf in [META_CF, SESSIONS_CF, VERSIONS_CF, EXPIRY_CF, NAMESPACES_CF] {
+            if !cfs.iter().any(|name| name == cf) {
+                cfs.push(cf.to_string());
+            }
+        }
+        // The compaction of the namespaces is stored in the database itself, so it is opened once
+        // to read it and then again with the column families configured accordingly.
+        let namespaces = Self {
+            rocks_db: DB::open_cf(&opts, path, &cfs)?,
+        }
+        .namespaces()?;
+        // the column families of a namespace are dropped after it is, and a crash in between
+        // leaves them behind
+        let orphans: Vec<String> = cfs
+            .iter()
+            .filter(|cf| cf.starts_with("ns:"))
+            .filter(|cf| {
+                !namespaces
+                    .iter()
+                    .any(|(name, _)| cf_names(name).contains(cf))
+            })
+            .cloned()
+            .collect();
+        let descriptors = cfs.into_iter().map(|cf| {
+            let compaction = namespaces
+                .iter()
+                .find(|(name, _)| cf_names(name).contains(&cf))
+                .map(|(_, compaction)| *compaction)
+                .unwrap_or_default();
+            ColumnFamilyDescriptor::new(cf, cf_options(compaction))
+        });
+        let mut database = Self {
+            rocks_db: DB::open_cf_descriptors(&opts, path, descriptors)?,
+        };
+        for cf in orphans {
+            database.rocks_db.drop_cf(&cf)?;
+        }
+        Ok(database)
+    }
+
+    /// Applies the command decided at `log_idx`. The effect of the command, the client's session
+    /// and the new applied index are written in the same batch, so a crash can never leave them
+    /// out of sync.
+    ///
+    /// A write that was already applied in its client's session is not applied again, but answered
+    /// with the result that it originally had. The commands of a batch are handled one by one, and
+    /// their results returned in order.
+    ///
+    /// On an error nothing is written, so the command can be applied again. The column families of
+    /// the namespaces that it drops are only dropped once it is written.
+    pub fn handle_command(
+        &mut self,
+        command: Command,
+        log_idx: u64,
+    ) -> Result<CommandResult, DatabaseError> {
+        let mut writes = Writes::default();
+        let result = match command.kv_cmd {
+            KVCommand::Batch(commands) => CommandResult::Batch(
+                commands
+                    .into_iter()
+                    .map(|command| self.handle(&mut writes, command, log_idx))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => self.handle(&mut writes, command, log_idx)?,
+        };
+        self.write(writes.batch, log_idx + 1)?;
+        self.drop_column_families(&writes.dropped)?;
+        Ok(result)
+    }
+
+    fn handle(
+        &mut self,
+        writes: &mut Writes,
+        command: Command,
+        log_idx: u64,
+    ) -> Result<CommandResult, DatabaseError> {
+        let session = match writes.sessions.get(&command.client_id) {
+            Some(session) => Some(session.clone()),
+            None => self.get_session(command.client_id)?,
+        };
+        match session {
+            // reads are idempotent and do not advance the client's session
+            Some(session)
+                if !command.kv_cmd.is_read() && session.has_applied(command.request_id) =>
+            {
+                if session.request_id == command.request_id {
+                    Ok(session.result())
+                } else {
+                    Ok(CommandResult::Superseded)
+                }
+            }
+            _ if command.kv_cmd.is_read() => self.apply(writes, command, log_idx),
+            _ => {
+                let (client_id, request_id) = (command.client_id, command.request_id);
+                let result = self.apply(writes, command, log_idx)?;
+                let session = ClientSession {
+                    request_id,
+                    log_idx,
+                    result: Some(result.clone()),
+                };
+                self.put_session(&mut writes.batch, client_id, &session)?;
+                writes.sessions.insert(client_id, session);
+                Ok(result)
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        writes: &mut Writes,
+        command: Command,
+        log_idx: u64,
+    ) -> Result<CommandResult, DatabaseError> {
+        if command.kv_cmd.is_read() {
+            return self.read(&command.namespace, command.kv_cmd);
+        }
+        match command.kv_cmd {
+            KVCommand::Expire { now } => {
+                self.expire(&mut writes.batch, now)?;
+                return Ok(CommandResult::Written(log_idx));
+            }
+            KVCommand::CreateNamespace { name, compaction } => {
+                if let Err(reason) = check_namespace_name(&name) {
+                    return Ok(CommandResult::Invalid(reason));
+                }
+                if self.has_namespace(&name)? {
+                    let reason = format!("namespace {} already exists", name);
+                    return Ok(CommandResult::Invalid(reason));
+                }
+                self.create_namespace(&mut writes.batch, &name, compaction)?;
+                return Ok(CommandResult::Written(log_idx));
+            }
+            KVCommand::DropNamespace(name) => {
+                if let Err(reason) = check_namespace_name(&name) {
+                    return Ok(CommandResult::Invalid(reason));
+                }
+                if !self.has_namespace(&name)? {
+                    return Ok(no_such_namespace(&name));
+                }
+                self.drop_namespace(&mut writes.batch, &name)?;
+                writes.dropped.push(name);
+                return Ok(CommandResult::Written(log_idx));
+            }
+            _ => {}
+        }
+        let keyspace = match self.keyspace(&command.namespace)? {
+            Some(keyspace) => keyspace,
+            None => return Ok(no_such_namespace(&command.namespace)),
+        };
+        let get = |key: &[u8]| match writes
+            .values
+            .get(&(command.namespace.clone(), key.to_vec()))
+        {
+            Some(Some(value)) if value.is_expired(self.get_clock()?) => Ok(None),
+            Some(value) => Ok(value.clone()),
+            None => self.get_versioned(&keyspace, key),
+        };
+        let (puts, result) = command.kv_cmd.execute(log_idx, get, || self.get_clock())?;
+        for (key, value) in puts {
+            self.put(&mut writes.batch, &keyspace, &key, value.as_ref());
+            writes
+                .values
+                .insert((command.namespace.clone(), key), value);
+        }
+        Ok(result)
+    }
+
+    /// Advances the replicated clock to `now` and deletes the keys that have expired by then.
+    fn expire(&self, batch: &mut WriteBatch, now: u64) -> Result<(), DatabaseError> {
+        let clock = self.get_clock()?.max(now);
+        batch.put_cf(self.meta_cf()?, CLOCK_KEY, clock.to_be_bytes());
+        let names = self.namespaces()?.into_iter().map(|(name, _)| name);
+        for namespace in std::iter::once(DEFAULT_NAMESPACE.to_string()).chain(names) {
+            let keyspace = self.column_families(&namespace)?;
+            for entry in self
+                .rocks_db
+                .iterator_cf(keyspace.expiry, IteratorMode::Start)
+            {
+                let (key, expires_at) = entry?;
+                if decode_u64(&expires_at)? <= clock {
+                    self.put(batch, &keyspace, &key, None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the column families of a namespace. Those left behind by a command that failed
+    /// before the namespace was recorded are empty, and reused.
+    fn create_namespace(
+        &mut self,
+        batch: &mut WriteBatch,
+        name: &str,
+        compaction: Compaction,
+    ) -> Result<(), DatabaseError> {
+        for cf in cf_names(name) {
+            if self.rocks_db.cf_handle(&cf).is_none() {
+                self.rocks_db.create_cf(&cf, &cf_options(compaction))?;
+            }
+        }
+        let data = serde_json::to_vec(&compaction)?;
+        batch.put_cf(self.namespaces_cf()?, name, data);
+        Ok(())
+    }
+
+    /// Removes a namespace from those recorded. Its keys are deleted all at once by dropping its
+    /// column families with [`Self::drop_column_families`], after the batch is written.
+    fn drop_namespace(&self, batch: &mut WriteBatch, name: &str) -> Result<(), DatabaseError> {
+        batch.delete_cf(self.namespaces_cf()?, name);
+        Ok(())
+    }
+
+    /// Drops the column families of namespaces that are no longer recorded. Those left behind by
+    /// a crash before they were dropped are dropped when the database is opened again.
+    fn drop_column_families(&mut self, names: &[Namespace]) -> Result<(), DatabaseError> {
+        for cf in names.iter().flat_map(|name| cf_names(name)) {
+            if self.rocks_db.cf_handle(&cf).is_some() {
+                self.rocks_db.drop_cf(&cf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a snapshot that covers the log up to `decided_idx`, replacing all namespaces and
+    /// keys.
+    pub fn apply_snapshot(
+        &mut self,
+        mut snapshot: KVSnapshot,
+        decided_idx: u64,
+    ) -> Result<(), DatabaseError> {
+        // the snapshot covers the log from its beginning
+        snapshot.resolve_pending();
+        let mut batch = WriteBatch::default();
+        let mut dropped = vec![];
+        for (name, _) in self.namespaces()? {
+            if !snapshot.namespaces.contains_key(&name) {
+                self.drop_namespace(&mut batch, &name)?;
+                dropped.push(name);
+            }
+        }
+        for (name, namespace) in &snapshot.namespaces {
+            if !self.has_namespace(name)? {
+                self.create_namespace(&mut batch, name, namespace.compaction)?;
+            }
+        }
+        batch.put_cf(self.meta_cf()?, CLOCK_KEY, snapshot.clock.to_be_bytes());
+        let first_idx = decided_idx - snapshot.num_entries;
+        self.replace_keys(
+            &mut batch,
+            DEFAULT_NAMESPACE,
+            snapshot.snapshotted,
+            first_idx,
+        )?;
+        for (name, namespace) in snapshot.namespaces {
+            self.replace_keys(&mut batch, &name, namespace.keys, first_idx)?;
+        }
+        for (client_id, mut session) in snapshot.sessions {
+            session.offset(first_idx);
+            self.put_session(&mut batch, client_id, &session)?;
+        }
+        self.write(batch, decided_idx)?;
+        self.drop_column_families(&dropped)
+    }
+
+    /// Replaces the keys of a namespace with those of a snapshot, whose versions are relative to
+    /// `first_idx`.
+    fn replace_keys(
+        &self,
+        batch: &mut WriteBatch,
+        namespace: &str,
+        keys: HashMap<Key, VersionedValue>,
+        first_idx: u64,
+    ) -> Result<(), DatabaseError> {
+        let keyspace = self.column_families(namespace)?;
+        for entry in self
+            .rocks_db
+            .iterator_cf(keyspace.keys, IteratorMode::Start)
+        {
+            let (key, _) = entry?;
+            if !keys.contains_key(&*key) {
+                self.put(batch, &keyspace, &key, None);
+            }
+        }
+        for (key, mut value) in keys {
+            value.version += first_idx;
+            self.put(batch, &keyspace, &key, Some(&value));
+        }
+        Ok(())
+    }
+
+    /// Returns all namespaces, keys and sessions as a snapshot that covers the log up to the
+    /// applied index, for a replica that joins the cluster. Expired keys that have not been
+    /// deleted yet are included, and hidden by the replicated clock as they are here.
+    pub fn snapshot(&self) -> Result<KVSnapshot, DatabaseError> {
+        let mut snapshot = KVSnapshot {
+            snapshotted: self.versioned_keys(DEFAULT_NAMESPACE)?,
+            deleted_keys: vec![],
+            namespaces: HashMap::new(),
+            increments: HashMap::new(),
+            increment_results: HashMap::new(),
+            pending: vec![],
+            sessions: HashMap::new(),
+            entries: vec![],
+            num_entries: self.get_applied_idx()?,
+            clock: self.get_clock()?,
+        };
+        for (name, compaction) in self.namespaces()? {
+            let keys = self.versioned_keys(&name)?;
+            let namespace = NamespaceSnapshot { compaction, keys };
+            snapshot.namespaces.insert(name, namespace);
+        }
+        for entry in self
+            .rocks_db
+            .iterator_cf(self.sessions_cf()?, IteratorMode::Start)
+        {
+            let (client_id, session) = entry?;
+            let session = serde_json::from_slice(&session)?;
+            snapshot.sessions.insert(decode_u64(&client_id)?, session);
+        }
+        Ok(snapshot)
+    }
+
+    /// Returns every key of a namespace with its version and expiry time.
+    fn versioned_keys(
+        &self,
+        namespace: &str,
+    ) -> Result<HashMap<Key, VersionedValue>, DatabaseError> {
+        let keyspace = self.column_families(namespace)?;
+        let mut keys = HashMap::new();
+        for entry in self
+            .rocks_db
+            .iterator_cf(keyspace.keys, IteratorMode::Start)
+        {
+            let (key, value) = entry?;
+            let value = VersionedValue {
+                value: value.into_vec(),
+                version: self.get_u64(keyspace.versions, &key)?.unwrap_or(0),
+                expires_at: self.get_u64(keyspace.expiry, &key)?,
+            };
+            keys.insert(key.into_vec(), value);
+        }
+        Ok(keys)
+    }
+
+    /// Returns the configuration that the replica moved to last, or `None` if it is still in the
+    /// one it was first started in.
+    pub fn get_configuration(&self) -> Result<Option<Configuration>, DatabaseError> {
+        match self.rocks_db.get_cf(self.meta_cf()?, CONFIGURATION_KEY)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Records that the replica moved to `configuration`, having applied the log up to where the
+    /// configuration starts, and stores the current state as its base state.
+    pub fn set_configuration(&self, configuration: &Configuration) -> Result<(), DatabaseError> {
+        let mut base = self.snapshot()?;
+        // the stop sign that ended the previous configuration takes up an index of the log
+        base.num_entries = configuration.log_offset;
+        let base = serde_json::to_vec(&base)?;
+        let meta_cf = self.meta_cf()?;
+        let mut batch = WriteBatch::default();
+        let num_chunks = self.get_u64(meta_cf, BASE_CHUNKS_KEY)?.unwrap_or(0);
+        for idx in 0..num_chunks {
+            batch.delete_cf(meta_cf, base_chunk_key(idx));
+        }
+        let chunks = base.chunks(BASE_CHUNK_LEN);
+        let num_chunks = chunks.len() as u64;
+        for (idx, chunk) in chunks.enumerate() {
+            batch.put_cf(meta_cf, base_chunk_key(idx as u64), chunk);
+        }
+        batch.put_cf(meta_cf, BASE_CHUNKS_KEY, num_chunks.to_be_bytes());
+        let data = serde_json::to_vec(configuration)?;
+        batch.put_cf(meta_cf, CONFIGURATION_KEY, data);
+        self.write(batch, configuration.log_offset)
+    }
+
+    /// Returns a chunk of the base state with the number of chunks, or `None` if there is no
+    /// such chunk. There is no base state before the replica moved to another configuration.
+    pub fn get_base_chunk(&self, idx: u64) -> Result<Option<(Vec<u8>, u64)>, DatabaseError> {
+        let meta_cf = self.meta_cf()?;
+        let num_chunks = self.get_u64(meta_cf, BASE_CHUNKS_KEY)?.unwrap_or(0);
+        if idx >= num_chunks {
+            return Ok(None);
+        }
+        match self.rocks_db.get_cf(meta_cf, base_chunk_key(idx))? {
+            Some(chunk) => Ok(Some((chunk, num_chunks))),
+            None => Err(DatabaseError::Corruption(format!(
+                "chunk {} of {} of the base state is missing",
+                idx, num_chunks
+            ))),
+        }
+    }
+
+    /// Returns the base state, as a snapshot that covers the log up to where the configuration
+    /// that the replica moved to last starts.
+    pub fn get_base(&self) -> Result<Option<KVSnapshot>, DatabaseError> {
+        let mut base = vec![];
+        let mut idx = 0;
+        while let Some((chunk, num_chunks)) = self.get_base_chunk(idx)? {
+            base.extend(chunk);
+            idx += 1;
+            if idx == num_chunks {
+                return Ok(Some(serde_json::from_slice(&base)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the index of the last applied log entry, or 0 if nothing has been applied yet.
+    pub fn get_applied_idx(&self) -> Result<u64, DatabaseError> {
+        Ok(self.get_u64(self.meta_cf()?, APPLIED_IDX_KEY)?.unwrap_or(0))
+    }
+
+    /// Serves a `Get`, `Scan` or `Prefix` command on the keys of `namespace`.
+    pub fn read(&self, namespace: &str, kv_cmd: KVCommand) -> Result<CommandResult, DatabaseError> {
+        let keyspace = match self.keyspace(namespace)? {
+            Some(keyspace) => keyspace,
+            None => return Ok(no_such_namespace(namespace)),
+        };
+        let result = match kv_cmd {
+            // would never get past `start`, which it returns as the key to continue from
+            KVCommand::Scan { limit: 0, .. } | KVCommand::Prefix { limit: 0, .. } => {
+                CommandResult::Invalid("the limit must be positive".to_string())
+            }
+            KVCommand::Get(key) => {
+                let value = self.get_versioned(&keyspace, &key)?;
+                CommandResult::Read(key, value)
+            }
+            KVCommand::Scan { start, end, limit } => {
+                let (entries, next) = self.iterate(&keyspace, &start, limit, |key| match &end {
+                    Some(end) => key < end.as_slice(),
+                    None => true,
+                })?;
+                CommandResult::Scan { entries, next }
+            }
+            KVCommand::Prefix {
+                prefix,
+                start,
+                limit,
+            } => {
+                let start = start.as_ref().unwrap_or(&prefix).max(&prefix);
+                let (entries, next) =
+                    self.iterate(&keyspace, start, limit, |key| key.starts_with(&prefix))?;
+                CommandResult::Scan { entries, next }
+            }
+            kv_cmd => panic!("not a read: {:?}", kv_cmd),
+        };
+        Ok(result)
+    }
+
+    /// Returns the keys from `start` on for which `in_range` holds, stopping at the first one for
+    /// which it does not. If there are more than `limit` of them, also returns the key after the
+    /// last one returned.
+    fn iterate(
+        &self,
+        keyspace: &Keyspace,
+        start: &[u8],
+        limit: usize,
+        in_range: impl Fn(&[u8]) -> bool,
+    ) -> Result<(Vec<KeyValue>, Option<Key>), DatabaseError> {
+        let clock = self.get_clock()?;
+        let mut entries = Vec::new();
+        let mode = IteratorMode::From(start, Direction::Forward);
+        for entry in self.rocks_db.iterator_cf(keyspace.keys, mode) {
+            let (key, value) = entry?;
+            if !in_range(&key) {
+                break;
+            }
+            let expires_at = self.get_u64(keyspace.expiry, &key)?;
+            if matches!(expires_at, Some(expires_at) if expires_at <= clock) {
+                continue;
+            }
+            if entries.len() == limit {
+                return Ok((entries, Some(key.into_vec())));
+            }
+            let (key, value) = (key.into_vec(), value.into_vec());
+            entries.push(KeyValue { key, value });
+        }
+        Ok((entries, None))
+    }
+
+    fn get_versioned(
+        &self,
+        keyspace: &Keyspace,
+        key: &[u8],
+    ) -> Result<Option<VersionedValue>, DatabaseError> {
+        let value = match self.rocks_db.get_cf(keyspace.keys, key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // keys written before versions were tracked have version 0
+        let version = self.get_u64(keyspace.versions, key)?.unwrap_or(0);
+        let expires_at = self.get_u64(keyspace.expiry, key)?;
+        let value = VersionedValue {
+            value,
+            version,
+            expires_at,
+        };
+        // expired keys are only deleted by the next `Expire` command
+        if value.is_expired(self.get_clock()?) {
+            return Ok(None);
+        }
+        Ok(Some(value))
+    }
+
+    /// Returns the replicated clock, the latest timestamp of an applied `Expire` command.
+    fn get_clock(&self) -> Result<u64, DatabaseError> {
+        Ok(self.get_u64(self.meta_cf()?, CLOCK_KEY)?.unwrap_or(0))
+    }
+
+    fn get_u64(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<u64>, DatabaseError> {
+        match self.rocks_db.get_cf(cf, key)? {
+            Some(bytes) => Ok(Some(decode_u64(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes `value` with its version and expiry to `key`, or deletes it if `value` is `None`.
+    fn put(
+        &self,
+        batch: &mut WriteBatch,
+        keyspace: &Keyspace,
+        key: &[u8],
+        value: Option<&VersionedValue>,
+    ) {
+        match value {
+            Some(value) => {
+                batch.put_cf(keyspace.keys, key, &value.value);
+                batch.put_cf(keyspace.versions, key, value.version.to_be_bytes());
+                match value.expires_at {
+                    Some(expires_at) => {
+                        batch.put_cf(keyspace.expiry, key, expires_at.to_be_bytes())
+                    }
+                    None => batch.delete_cf(keyspace.expiry, key),
+                }
+            }
+            None => {
+                batch.delete_cf(keyspace.keys, key);
+                batch.delete_cf(keyspace.versions, key);
+                batch.delete_cf(keyspace.expiry, key);
+            }
+        }
+    }
+
+    fn has_namespace(&self, namespace: &str) -> Result<bool, DatabaseError> {
+        if namespace == DEFAULT_NAMESPACE {
+            return Ok(true);
+        }
+        let compaction = self.rocks_db.get_cf(self.namespaces_cf()?, namespace)?;
+        Ok(compaction.is_some())
+    }
+
+    /// Returns the namespaces other than the default one, with their compaction.
+    fn namespaces(&self) -> Result<Vec<(Namespace, Compaction)>, DatabaseError> {
+        let mut namespaces = Vec::new();
+        for entry in self
+            .rocks_db
+            .iterator_cf(self.namespaces_cf()?, IteratorMode::Start)
+        {
+            let (name, compaction) = entry?;
+            let name = String::from_utf8(name.into_vec()).map_err(|_| {
+                DatabaseError::Corruption("namespace name is not UTF-8".to_string())
+            })?;
+            namespaces.push((name, serde_json::from_slice(&compaction)?));
+        }
+        Ok(namespaces)
+    }
+
+    /// Returns the column families of `namespace`, or `None` if it does not exist.
+    fn keyspace(&self, namespace: &str) -> Result<Option<Keyspace<'_>>, DatabaseError> {
+        if self.has_namespace(namespace)? {
+            Ok(Some(self.column_families(namespace)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the column families of `namespace`, which must exist.
+    fn column_families(&self, namespace: &str) -> Result<Keyspace<'_>, DatabaseError> {
+        let [keys, versions, expiry] = cf_names(namespace);
+        Ok(Keyspace {
+            keys: self.cf(&keys)?,
+            versions: self.cf(&versions)?,
+            expiry: self.cf(&expiry)?,
+        })
+    }
+
+    fn get_session(&self, client_id: ClientId) -> Result<Option<ClientSession>, DatabaseError> {
+        match self
+            .rocks_db
+            .get_cf(self.sessions_cf()?, client_id.to_be_bytes())?
+        {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_session(
+        &self,
+        batch: &mut WriteBatch,
+        client_id: ClientId,
+        session: &ClientSession,
+    ) -> Result<(), DatabaseError> {
+        let data = serde_json::to_vec(session)?;
+        batch.put_cf(self.sessions_cf()?, client_id.to_be_bytes(), data);
+        Ok(())
+    }
+
+    fn write(&self, mut batch: WriteBatch, decided_idx: u64) -> Result<(), DatabaseError> {
+        batch.put_cf(self.meta_cf()?, APPLIED_IDX_KEY, decided_idx.to_be_bytes());
+        self.rocks_db.write(batch)?;
+        Ok(())
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily, DatabaseError> {
+        self.rocks_db
+            .cf_handle(name)
+            .ok_or_else(|| DatabaseError::Corruption(format!("missing column family {}", name)))
+    }
+
+    fn meta_cf(&self) -> Result<&ColumnFamily, DatabaseError> {
+        self.cf(META_CF)
+    }
+
+    fn sessions_cf(&self) -> Result<&ColumnFamily, DatabaseError> {
+        self.cf(SESSIONS_CF)
+    }
+
+    fn namespaces_cf(&self) -> Result<&ColumnFamily, DatabaseError> {
+        self.cf(NAMESPACES_CF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{Guard, TxnOp, Value, SERVER_CLIENT_ID};
+    use omnipaxos::storage::Snapshot;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A database in a directory of its own, which is deleted with it.
+    struct TempDatabase {
+        path: PathBuf,
+        database: Option<Database>,
+        next_idx: u64,
     }
 
-    pub fn handle_command(&self, command: KVCommand) -> Option<String> {
-        match command {
-            KVCommand::Put(KeyValue { key, value }) => {
-                self.put(&key, &value);
-                None
+    impl TempDatabase {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "kv_store-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            let database = Database::new(path.to_str().unwrap()).unwrap();
+            Self {
+                path,
+                database: Some(database),
+                next_idx: 0,
             }
-            KVCommand::Delete(key) => {
-                self.delete(&key);
-                None
+        }
+
+        fn db(&mut self) -> &mut Database {
+            self.database.as_mut().unwrap()
+        }
+
+        /// Closes the database and opens it again, like after a restart.
+        fn reopen(&mut self) {
+            self.database = None;
+            self.database = Some(Database::new(self.path.to_str().unwrap()).unwrap());
+        }
+
+        /// Applies the command as the next entry of the log.
+        fn handle(
+            &mut self,
+            client_id: ClientId,
+            request_id: u64,
+            kv_cmd: KVCommand,
+        ) -> CommandResult {
+            self.handle_in(DEFAULT_NAMESPACE, client_id, request_id, kv_cmd)
+        }
+
+        fn handle_in(
+            &mut self,
+            namespace: &str,
+            client_id: ClientId,
+            request_id: u64,
+            kv_cmd: KVCommand,
+        ) -> CommandResult {
+            let command = command(namespace, client_id, request_id, kv_cmd);
+            let log_idx = self.next_idx;
+            self.next_idx += 1;
+            self.db().handle_command(command, log_idx).unwrap()
+        }
+
+        fn get(&mut self, namespace: &str, key: &str) -> Option<(Value, u64)> {
+            match self
+                .db()
+                .read(namespace, KVCommand::Get(key.into()))
+                .unwrap()
+            {
+                CommandResult::Read(_, value) => value.map(|value| (value.value, value.version)),
+                result => panic!("not a read result: {:?}", result),
             }
-            KVCommand::Get(key) => self.get(key.as_str()),
         }
     }
 
-    fn get(&self, key: &str) -> Option<String> {
-        match self.rocks_db.get(key.as_bytes()) {
-            Ok(Some(value)) => {
-                let value = String::from_utf8(value).unwrap();
-                Some(value)
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            self.database = None;
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn command(
+        namespace: &str,
+        client_id: ClientId,
+        request_id: u64,
+        kv_cmd: KVCommand,
+    ) -> Command {
+        Command {
+            client_id,
+            request_id,
+            coordinator_id: 1,
+            namespace: namespace.to_string(),
+            kv_cmd,
+        }
+    }
+
+    fn put(key: &str, value: &str) -> KVCommand {
+        KVCommand::Put {
+            key: key.into(),
+            value: value.into(),
+            if_version: None,
+            ttl_ms: None,
+        }
+    }
+
+    fn scan(start: &str, limit: usize) -> KVCommand {
+        KVCommand::Scan {
+            start: start.into(),
+            end: None,
+            limit,
+        }
+    }
+
+    #[test]
+    fn writes_are_versioned_by_their_log_index() {
+        let mut db = TempDatabase::new();
+        assert_eq!(db.handle(1, 1, put("k", "a")), CommandResult::Written(0));
+        assert_eq!(db.handle(1, 2, put("k", "b")), CommandResult::Written(1));
+        assert_eq!(db.get("", "k"), Some((b"b".to_vec(), 1)));
+        assert_eq!(db.db().get_applied_idx().unwrap(), 2);
+    }
+
+    #[test]
+    fn retries_are_answered_from_the_session() {
+        let mut db = TempDatabase::new();
+        db.handle(1, 1, put("k", "a"));
+        db.handle(
+            1,
+            2,
+            KVCommand::Append {
+                key: "k".into(),
+                suffix: "b".into(),
+            },
+        );
+        let updated = CommandResult::Updated {
+            log_idx: 1,
+            value: "ab".into(),
+        };
+        // not applied again
+        assert_eq!(
+            db.handle(
+                1,
+                2,
+                KVCommand::Append {
+                    key: "k".into(),
+                    suffix: "b".into()
+                }
+            ),
+            updated
+        );
+        assert_eq!(db.get("", "k"), Some((b"ab".to_vec(), 1)));
+        // the result of an older request is gone
+        assert_eq!(db.handle(1, 1, put("k", "a")), CommandResult::Superseded);
+        // sessions survive a restart
+        db.reopen();
+        assert_eq!(
+            db.handle(
+                1,
+                2,
+                KVCommand::Append {
+                    key: "k".into(),
+                    suffix: "b".into()
+                }
+            ),
+            updated
+        );
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let mut db = TempDatabase::new();
+        db.handle(1, 1, put("k", "a"));
+        let cas = |expected: &str, new: &str| KVCommand::CompareAndSwap {
+            key: "k".into(),
+            expected: Some(expected.into()),
+            new: Some(new.into()),
+        };
+        let result = db.handle(1, 2, cas("x", "b"));
+        let failed = CommandResult::CompareAndSwap {
+            log_idx: 1,
+            swapped: false,
+            current: Some("a".into()),
+        };
+        assert_eq!(result, failed);
+        let result = db.handle(1, 3, cas("a", "b"));
+        let swapped = CommandResult::CompareAndSwap {
+            log_idx: 2,
+            swapped: true,
+            current: Some("b".into()),
+        };
+        assert_eq!(result, swapped);
+        assert_eq!(db.get("", "k"), Some((b"b".to_vec(), 2)));
+    }
+
+    #[test]
+    fn failed_guard_writes_nothing() {
+        let mut db = TempDatabase::new();
+        db.handle(1, 1, put("k", "a"));
+        let txn = KVCommand::Txn {
+            guards: vec![Guard::Absent("j".into()), Guard::Absent("k".into())],
+            ops: vec![TxnOp::Put(KeyValue {
+                key: "j".into(),
+                value: "x".into(),
+            })],
+        };
+        let result = db.handle(1, 2, txn);
+        assert_eq!(
+            result,
+            CommandResult::Txn {
+                log_idx: 1,
+                failed_guard: Some(1)
             }
-            Ok(None) => None,
-            Err(e) => panic!("failed to get value: {}", e),
+        );
+        assert_eq!(db.get("", "j"), None);
+    }
+
+    #[test]
+    fn scans_are_paginated() {
+        let mut db = TempDatabase::new();
+        for (i, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            db.handle(1, i as u64, put(key, key));
         }
+        let keys = |result| match result {
+            CommandResult::Scan { entries, next } => (
+                entries
+                    .into_iter()
+                    .map(|entry| entry.key)
+                    .collect::<Vec<_>>(),
+                next,
+            ),
+            result => panic!("not a scan result: {:?}", result),
+        };
+        let page = keys(db.db().read("", scan("b", 2)).unwrap());
+        assert_eq!(
+            page,
+            (vec![b"b".to_vec(), b"c".to_vec()], Some(b"d".to_vec()))
+        );
+        let page = keys(db.db().read("", scan("d", 2)).unwrap());
+        assert_eq!(page, (vec![b"d".to_vec()], None));
+        let prefix = KVCommand::Prefix {
+            prefix: "c".into(),
+            start: None,
+            limit: 5,
+        };
+        let page = keys(db.db().read("", prefix).unwrap());
+        assert_eq!(page, (vec![b"c".to_vec()], None));
+        assert!(matches!(
+            db.db().read("", scan("a", 0)).unwrap(),
+            CommandResult::Invalid(_)
+        ));
     }
 
-    fn put(&self, key: &str, value: &str) {
-        match self.rocks_db.put(key.as_bytes(), value.as_bytes()) {
-            Ok(_) => {}
-            Err(e) => panic!("failed to put value: {}", e),
+    #[test]
+    fn expired_keys_are_hidden() {
+        let mut db = TempDatabase::new();
+        db.handle(SERVER_CLIENT_ID, 100, KVCommand::Expire { now: 100 });
+        let ttl = KVCommand::Put {
+            key: "k".into(),
+            value: "v".into(),
+            if_version: None,
+            ttl_ms: Some(10),
+        };
+        db.handle(1, 1, ttl);
+        db.handle(1, 2, put("j", "v"));
+        assert!(db.get("", "k").is_some());
+        // the clock is only advanced by the log
+        db.handle(SERVER_CLIENT_ID, 110, KVCommand::Expire { now: 110 });
+        assert_eq!(db.get("", "k"), None);
+        let page = db.db().read("", scan("", 10)).unwrap();
+        let j = KeyValue {
+            key: "j".into(),
+            value: "v".into(),
+        };
+        assert_eq!(
+            page,
+            CommandResult::Scan {
+                entries: vec![j],
+                next: None
+            }
+        );
+    }
+
+    #[test]
+    fn updates_keep_the_ttl_and_puts_replace_it() {
+        let mut db = TempDatabase::new();
+        db.handle(SERVER_CLIENT_ID, 100, KVCommand::Expire { now: 100 });
+        for (i, key) in ["n", "s", "k"].into_iter().enumerate() {
+            let ttl = KVCommand::Put {
+                key: key.into(),
+                value: "1".into(),
+                if_version: None,
+                ttl_ms: Some(10),
+            };
+            db.handle(1, 1 + i as u64, ttl);
         }
+        db.handle(
+            1,
+            4,
+            KVCommand::Increment {
+                key: "n".into(),
+                delta: 1,
+            },
+        );
+        db.handle(
+            1,
+            5,
+            KVCommand::Append {
+                key: "s".into(),
+                suffix: "0".into(),
+            },
+        );
+        db.handle(1, 6, put("k", "2"));
+        assert_eq!(db.get("", "n"), Some((b"2".to_vec(), 4)));
+        assert_eq!(db.get("", "s"), Some((b"10".to_vec(), 5)));
+        db.handle(SERVER_CLIENT_ID, 110, KVCommand::Expire { now: 110 });
+        assert_eq!(db.get("", "n"), None);
+        assert_eq!(db.get("", "s"), None);
+        assert_eq!(db.get("", "k"), Some((b"2".to_vec(), 6)));
+    }
+
+    #[test]
+    fn dropped_namespaces_lose_their_keys() {
+        let mut db = TempDatabase::new();
+        let create = KVCommand::CreateNamespace {
+            name: "ns".into(),
+            compaction: Compaction::Level,
+        };
+        assert_eq!(db.handle(1, 1, create.clone()), CommandResult::Written(0));
+        db.handle_in("ns", 1, 2, put("k", "v"));
+        assert!(db.get("ns", "k").is_some());
+        assert_eq!(
+            db.handle(1, 3, KVCommand::DropNamespace("ns".into())),
+            CommandResult::Written(2)
+        );
+        assert!(db.db().rocks_db.cf_handle("ns:ns").is_none());
+        let result = db.handle_in("ns", 1, 4, put("k", "v"));
+        assert_eq!(result, no_such_namespace("ns"));
+        db.handle(1, 5, create);
+        assert_eq!(db.get("ns", "k"), None);
     }
 
-    fn delete(&self, key: &str) {
-        match self.rocks_db.delete(key.as_bytes()) {
-            Ok(_) => {}
-            Err(e) => panic!("failed to delete value: {}", e),
+    #[test]
+    fn column_families_of_a_dropped_namespace_are_dropped_on_reopen() {
+        let mut db = TempDatabase::new();
+        let create = KVCommand::CreateNamespace {
+            name: "ns".into(),
+            compaction: Compaction::Level,
+        };
+        db.handle(1, 1, create.clone());
+        db.handle_in("ns", 1, 2, put("k", "v"));
+        // a crash after the drop was written, but before the column families were dropped
+        let mut batch = WriteBatch::default();
+        db.db().drop_namespace(&mut batch, "ns").unwrap();
+        db.db().write(batch, 3).unwrap();
+        assert!(db.db().rocks_db.cf_handle("ns:ns").is_some());
+
+        db.reopen();
+        for cf in cf_names("ns") {
+            assert!(db.db().rocks_db.cf_handle(&cf).is_none());
+        }
+        db.next_idx = 3;
+        db.handle(1, 3, create);
+        assert_eq!(db.get("ns", "k"), None);
+    }
+
+    fn configuration(log_offset: u64) -> Configuration {
+        Configuration {
+            id: 2,
+            nodes: vec![1, 2, 3],
+            log_offset,
+        }
+    }
+
+    #[test]
+    fn base_state_is_stored_in_chunks() {
+        let mut db = TempDatabase::new();
+        let large = "x".repeat(BASE_CHUNK_LEN / 2);
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            db.handle(1, i as u64, put(key, &large));
+        }
+        db.db().set_configuration(&configuration(4)).unwrap();
+        assert_eq!(db.db().get_applied_idx().unwrap(), 4);
+        let (_, num_chunks) = db.db().get_base_chunk(0).unwrap().unwrap();
+        assert!(num_chunks > 1);
+        assert_eq!(db.db().get_base_chunk(num_chunks).unwrap(), None);
+        let base = db.db().get_base().unwrap().unwrap();
+        assert_eq!(base.num_entries, 4);
+        assert_eq!(base.snapshotted[b"c".as_slice()].version, 2);
+
+        // the chunks of the previous base state are deleted
+        db.next_idx = 4;
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            let delete = KVCommand::Delete {
+                key: key.into(),
+                if_version: None,
+            };
+            db.handle(1, 3 + i as u64, delete);
+        }
+        db.db().set_configuration(&configuration(8)).unwrap();
+        assert_eq!(db.db().get_base_chunk(1).unwrap(), None);
+        let base = db.db().get_base().unwrap().unwrap();
+        assert!(base.snapshotted.is_empty());
+    }
+
+    #[test]
+    fn snapshots_of_later_configurations_build_on_the_base_state() {
+        let mut db = TempDatabase::new();
+        db.handle(1, 1, put("a", "1"));
+        db.handle(1, 2, put("b", "1"));
+        // the stop sign takes up index 2
+        db.db().set_configuration(&configuration(3)).unwrap();
+        let log = [
+            command("", 1, 3, put("b", "2")),
+            command(
+                "",
+                2,
+                1,
+                KVCommand::Increment {
+                    key: "n".into(),
+                    delta: 1,
+                },
+            ),
+        ];
+
+        // a replica that joined the configuration and catches up with a snapshot of its log
+        let mut joined = TempDatabase::new();
+        let base = db.db().get_base().unwrap().unwrap();
+        joined.db().apply_snapshot(base, 3).unwrap();
+        joined.db().set_configuration(&configuration(3)).unwrap();
+        let mut snapshot = joined.db().get_base().unwrap().unwrap();
+        snapshot.merge(KVSnapshot::create(&log));
+        joined.db().apply_snapshot(snapshot, 5).unwrap();
+
+        for (log_idx, command) in (3..).zip(log) {
+            db.db().handle_command(command, log_idx).unwrap();
+        }
+        for key in ["a", "b", "n"] {
+            assert_eq!(joined.get("", key), db.get("", key));
         }
+        assert_eq!(joined.get("", "b"), Some((b"2".to_vec(), 3)));
+        assert_eq!(joined.db().get_applied_idx().unwrap(), 5);
+        let retry = command(
+            "",
+            2,
+            1,
+            KVCommand::Increment {
+                key: "n".into(),
+                delta: 1,
+            },
+        );
+        let updated = CommandResult::Updated {
+            log_idx: 4,
+            value: "1".into(),
+        };
+        assert_eq!(joined.db().handle_command(retry, 5).unwrap(), updated);
     }
 }
@@ -2,62 +2,814 @@ use std::collections::HashMap;
 use omnipaxos::storage::{Entry, Snapshot};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Keys and values are arbitrary bytes.
+pub type Key = Vec<u8>;
+pub type Value = Vec<u8>;
+/// Name of a namespace, a separate set of keys that is stored in its own column families.
+pub type Namespace = String;
+
+/// The namespace that always exists, and that commands address unless they name another one.
+pub const DEFAULT_NAMESPACE: &str = "";
+
+/// The result of a command on a namespace that does not exist.
+pub fn no_such_namespace(namespace: &str) -> CommandResult {
+    CommandResult::Invalid(format!("namespace {} does not exist", namespace))
+}
+
+/// Checks that a namespace can be created under `name`.
+pub fn check_namespace_name(name: &str) -> Result<(), String> {
+    if name == DEFAULT_NAMESPACE {
+        Err("the default namespace always exists".to_string())
+    } else if name.contains(':') {
+        // separates the namespace from the suffixes of its column families
+        Err("namespaces cannot contain ':'".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// How the keys of a namespace are compacted by RocksDB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compaction {
+    #[default]
+    Level,
+    Universal,
+    /// Deletes the oldest files once the namespace outgrows its size limit.
+    Fifo,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct KeyValue {
-    pub key: String,
-    pub value: String,
+    pub key: Key,
+    pub value: Value,
+}
+
+/// A value together with its version, the index of the log entry that last wrote it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedValue {
+    pub value: Value,
+    pub version: u64,
+    /// Time of the replicated clock at which the key expires, if it was written with a TTL.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
+impl VersionedValue {
+    /// Returns true if the key has expired at time `clock` of the replicated clock.
+    pub fn is_expired(&self, clock: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= clock,
+            None => false,
+        }
+    }
+}
+
+/// A condition on the current value of a key that must hold for a transaction to commit.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum KVCommand {
+pub enum Guard {
+    Equals(KeyValue),
+    Absent(Key),
+    VersionEquals { key: Key, version: u64 },
+}
+
+impl Guard {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Guard::Equals(KeyValue { key, .. }) => key,
+            Guard::Absent(key) => key,
+            Guard::VersionEquals { key, .. } => key,
+        }
+    }
+
+    pub fn holds(&self, current: Option<&VersionedValue>) -> bool {
+        match (self, current) {
+            (Guard::Equals(KeyValue { value, .. }), Some(current)) => current.value == *value,
+            (Guard::Absent(_), current) => current.is_none(),
+            (Guard::VersionEquals { version, .. }, Some(current)) => current.version == *version,
+            (_, None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TxnOp {
     Put(KeyValue),
-    Delete(String),
-    Get(String),
+    Delete(Key),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KVCommand {
+    /// Only written if the key is at `if_version`, when given. With a TTL, the key expires once
+    /// the replicated clock has advanced by `ttl_ms`. Like the writes of `CompareAndSwap` and
+    /// `Txn`, it replaces the TTL of the key along with its value.
+    Put {
+        key: Key,
+        value: Value,
+        #[serde(default)]
+        if_version: Option<u64>,
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+    },
+    /// Only deleted if the key is at `if_version`, when given.
+    Delete {
+        key: Key,
+        #[serde(default)]
+        if_version: Option<u64>,
+    },
+    Get(Key),
+    /// Sets `key` to `new` if its value is `expected`. `None` stands for an absent key.
+    CompareAndSwap {
+        key: Key,
+        expected: Option<Value>,
+        new: Option<Value>,
+    },
+    /// Applies all `ops` atomically if every guard holds, and none of them otherwise.
+    Txn { guards: Vec<Guard>, ops: Vec<TxnOp> },
+    /// Adds `delta` to the integer value of `key`, where an absent key counts as 0. Wraps around
+    /// on overflow. The key keeps its TTL.
+    Increment { key: Key, delta: i64 },
+    /// Appends `suffix` to the value of `key`, where an absent key counts as empty. The key keeps
+    /// its TTL.
+    Append { key: Key, suffix: Value },
+    /// Reads up to `limit` keys from `start` up to, but excluding, `end` in key order.
+    Scan {
+        start: Key,
+        end: Option<Key>,
+        limit: usize,
+    },
+    /// Reads up to `limit` keys that start with `prefix` in key order, from the key `start` on
+    /// if given.
+    Prefix {
+        prefix: Key,
+        start: Option<Key>,
+        limit: usize,
+    },
+    /// Advances the replicated clock to `now`, a timestamp in milliseconds proposed by the
+    /// leader, and deletes the keys that have expired by then. The clock never goes backwards.
+    Expire { now: u64 },
+    /// Creates an empty namespace, whose keys are compacted as given.
+    CreateNamespace {
+        name: Namespace,
+        #[serde(default)]
+        compaction: Compaction,
+    },
+    /// Deletes a namespace and all its keys at once.
+    DropNamespace(Namespace),
+    /// Client writes that a server received at about the same time and proposed as one log
+    /// entry. They are applied in order, each in its own client's session. No two of them write
+    /// the same key, which would otherwise end up at the same version twice.
+    Batch(Vec<Command>),
+    /// Moves the cluster to a new configuration with the given nodes. Not a command of the log,
+    /// but proposed by the leader as the stop sign that ends the log of the current configuration.
+    Reconfigure { nodes: Vec<u64> },
+}
+
+/// Client id under which the servers propose their own commands, such as `Expire`.
+pub const SERVER_CLIENT_ID: ClientId = 0;
+
+/// The result of applying a decided command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CommandResult {
+    /// The write took effect at the given log index, which is the new version of the key.
+    Written(u64),
+    Read(Key, Option<VersionedValue>),
+    /// `next` is the key to continue the scan from if there are more keys in the range.
+    Scan {
+        entries: Vec<KeyValue>,
+        next: Option<Key>,
+    },
+    /// `current` is the value of the key after the compare-and-swap.
+    CompareAndSwap {
+        log_idx: u64,
+        swapped: bool,
+        current: Option<Value>,
+    },
+    /// `failed_guard` is the index of the first guard that did not hold, in which case nothing
+    /// was written.
+    Txn {
+        log_idx: u64,
+        failed_guard: Option<usize>,
+    },
+    /// The value of the key after an `Increment` or `Append` that took effect at `log_idx`.
+    Updated { log_idx: u64, value: Value },
+    /// The command could not be applied to the current value, e.g. incrementing a value that is
+    /// not an integer. Nothing was written.
+    Invalid(String),
+    /// A conditional write was not made because the key is at another version, or absent if
+    /// `None`.
+    VersionMismatch(Option<u64>),
+    /// A retry of a request older than the client's latest one, whose result is no longer known.
+    Superseded,
+    /// The results of the commands of a `Batch`, in order.
+    Batch(Vec<CommandResult>),
 }
 
-impl Entry for KVCommand {
+impl CommandResult {
+    /// Adds `offset` to the log indexes and versions in a result from a snapshot, where they are
+    /// relative to the first entry covered by the snapshot.
+    pub fn offset(&mut self, offset: u64) {
+        match self {
+            CommandResult::Written(log_idx)
+            | CommandResult::CompareAndSwap { log_idx, .. }
+            | CommandResult::Txn { log_idx, .. }
+            | CommandResult::Updated { log_idx, .. } => *log_idx += offset,
+            CommandResult::Read(_, Some(value)) => value.version += offset,
+            CommandResult::VersionMismatch(Some(version)) => *version += offset,
+            CommandResult::Batch(results) => {
+                for result in results {
+                    result.offset(offset);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A write to a key, `None` deleting it.
+pub type Write = (Key, Option<VersionedValue>);
+
+impl KVCommand {
+    /// Returns true for commands that do not modify the database.
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            KVCommand::Get(_) | KVCommand::Scan { .. } | KVCommand::Prefix { .. }
+        )
+    }
+
+    /// Returns true for the writes that a server may batch with others, which are those that
+    /// only write the keys they name.
+    pub fn is_batchable(&self) -> bool {
+        matches!(
+            self,
+            KVCommand::Put { .. }
+                | KVCommand::Delete { .. }
+                | KVCommand::CompareAndSwap { .. }
+                | KVCommand::Txn { .. }
+                | KVCommand::Increment { .. }
+                | KVCommand::Append { .. }
+        )
+    }
+
+    /// Returns the keys that a batchable command may write.
+    pub fn written_keys(&self) -> Vec<&[u8]> {
+        match self {
+            KVCommand::Put { key, .. }
+            | KVCommand::Delete { key, .. }
+            | KVCommand::CompareAndSwap { key, .. }
+            | KVCommand::Increment { key, .. }
+            | KVCommand::Append { key, .. } => vec![key],
+            KVCommand::Txn { ops, .. } => ops
+                .iter()
+                .map(|op| match op {
+                    TxnOp::Put(KeyValue { key, .. }) | TxnOp::Delete(key) => key.as_slice(),
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Executes the write command decided at `log_idx` against the current values of the keys, as
+    /// returned by `get`, and the replicated clock, as returned by `clock`. Returns the writes to
+    /// make and the result of the command. Everything is read before anything is written, so a
+    /// failed read leaves nothing half done.
+    ///
+    /// Reads, `Expire` and the namespace commands are served by the database instead, which
+    /// keeps the keys in order. The commands of a `Batch` are executed one by one.
+    pub fn execute<E>(
+        &self,
+        log_idx: u64,
+        mut get: impl FnMut(&[u8]) -> Result<Option<VersionedValue>, E>,
+        clock: impl FnOnce() -> Result<u64, E>,
+    ) -> Result<(Vec<Write>, CommandResult), E> {
+        let versioned = |value: &Value| VersionedValue {
+            value: value.clone(),
+            version: log_idx,
+            expires_at: None,
+        };
+        let execution = match self {
+            KVCommand::Put {
+                key,
+                value,
+                if_version,
+                ttl_ms,
+            } => {
+                let mut value = versioned(value);
+                if let Some(ttl_ms) = ttl_ms {
+                    let clock = clock()?;
+                    // The clock starts with the first `Expire`, which a leader proposes as soon as
+                    // it is elected. Measured from 0, the key would expire once it starts.
+                    if clock == 0 {
+                        let reason = "the replicated clock has not started yet".to_string();
+                        return Ok((vec![], CommandResult::Invalid(reason)));
+                    }
+                    value.expires_at = Some(clock.saturating_add(*ttl_ms));
+                }
+                let write = (key.clone(), Some(value));
+                write_if_version(write, *if_version, log_idx, &mut get)?
+            }
+            KVCommand::Delete { key, if_version } => {
+                write_if_version((key.clone(), None), *if_version, log_idx, &mut get)?
+            }
+            KVCommand::CompareAndSwap { key, expected, new } => {
+                let current = get(key)?.map(|v| v.value);
+                let swapped = current == *expected;
+                let (writes, current) = if swapped {
+                    (vec![(key.clone(), new.as_ref().map(versioned))], new.clone())
+                } else {
+                    (vec![], current)
+                };
+                let result = CommandResult::CompareAndSwap {
+                    log_idx,
+                    swapped,
+                    current,
+                };
+                (writes, result)
+            }
+            KVCommand::Txn { guards, ops } => {
+                let mut failed_guard = None;
+                for (i, guard) in guards.iter().enumerate() {
+                    if !guard.holds(get(guard.key())?.as_ref()) {
+                        failed_guard = Some(i);
+                        break;
+                    }
+                }
+                let writes = match failed_guard {
+                    Some(_) => vec![],
+                    None => ops
+                        .iter()
+                        .map(|op| match op {
+                            TxnOp::Put(KeyValue { key, value }) => {
+                                (key.clone(), Some(versioned(value)))
+                            }
+                            TxnOp::Delete(key) => (key.clone(), None),
+                        })
+                        .collect(),
+                };
+                let result = CommandResult::Txn {
+                    log_idx,
+                    failed_guard,
+                };
+                (writes, result)
+            }
+            KVCommand::Increment { key, delta } => {
+                let current = get(key)?;
+                let expires_at = current.as_ref().and_then(|current| current.expires_at);
+                let current = match current {
+                    Some(current) => std::str::from_utf8(&current.value)
+                        .ok()
+                        .and_then(|value| value.parse::<i64>().ok()),
+                    None => Some(0),
+                };
+                match current {
+                    Some(current) => {
+                        let value = current.wrapping_add(*delta).to_string().into_bytes();
+                        let updated = VersionedValue { expires_at, ..versioned(&value) };
+                        let write = (key.clone(), Some(updated));
+                        (vec![write], CommandResult::Updated { log_idx, value })
+                    }
+                    None => {
+                        let key = String::from_utf8_lossy(key);
+                        let reason = format!("the value of {} is not an integer", key);
+                        (vec![], CommandResult::Invalid(reason))
+                    }
+                }
+            }
+            KVCommand::Append { key, suffix } => {
+                let (mut value, expires_at) = match get(key)? {
+                    Some(current) => (current.value, current.expires_at),
+                    None => (vec![], None),
+                };
+                value.extend_from_slice(suffix);
+                let updated = VersionedValue { expires_at, ..versioned(&value) };
+                let write = (key.clone(), Some(updated));
+                (vec![write], CommandResult::Updated { log_idx, value })
+            }
+            KVCommand::Get(_)
+            | KVCommand::Scan { .. }
+            | KVCommand::Prefix { .. }
+            | KVCommand::Expire { .. }
+            | KVCommand::CreateNamespace { .. }
+            | KVCommand::DropNamespace(_)
+            | KVCommand::Batch(_)
+            | KVCommand::Reconfigure { .. } => unreachable!("not executed per key"),
+        };
+        Ok(execution)
+    }
+}
+
+/// Makes `write` if its key is at `if_version`, or unconditionally if no version is given.
+fn write_if_version<E>(
+    write: Write,
+    if_version: Option<u64>,
+    log_idx: u64,
+    get: &mut impl FnMut(&[u8]) -> Result<Option<VersionedValue>, E>,
+) -> Result<(Vec<Write>, CommandResult), E> {
+    if let Some(if_version) = if_version {
+        let version = get(&write.0)?.map(|v| v.version);
+        if version != Some(if_version) {
+            return Ok((vec![], CommandResult::VersionMismatch(version)));
+        }
+    }
+    Ok((vec![write], CommandResult::Written(log_idx)))
+}
+
+pub type ClientId = u64;
+/// Sequence number of a request within its client's session. Must increase monotonically.
+pub type RequestId = u64;
+
+/// A client command as it is replicated in the OmniPaxos log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Command {
+    pub client_id: ClientId,
+    pub request_id: RequestId,
+    /// The server that received the request and replies to the client once it is decided.
+    pub coordinator_id: u64,
+    /// The namespace of the keys that `kv_cmd` addresses.
+    #[serde(default)]
+    pub namespace: Namespace,
+    pub kv_cmd: KVCommand,
+}
+
+impl Command {
+    /// Returns the client commands in this log entry: those of a batch, or else the command
+    /// itself.
+    pub fn commands(&self) -> &[Command] {
+        match &self.kv_cmd {
+            KVCommand::Batch(commands) => commands,
+            _ => std::slice::from_ref(self),
+        }
+    }
+
+    /// Returns true if both commands may write the same key of the same namespace.
+    pub fn writes_same_key(&self, other: &Command) -> bool {
+        let keys = other.kv_cmd.written_keys();
+        self.namespace == other.namespace
+            && self.kv_cmd.written_keys().iter().any(|key| keys.contains(key))
+    }
+}
+
+impl Entry for Command {
     type Snapshot = KVSnapshot;
 }
 
+/// The latest request of a client that was applied, used to answer retries without re-applying them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientSession {
+    pub request_id: RequestId,
+    pub log_idx: u64,
+    /// The result that a retry of the request is answered with. Unknown for an increment that a
+    /// snapshot summed up before the value of its key was known, and for sessions stored before
+    /// results were, whose requests are answered as written at `log_idx`.
+    #[serde(default)]
+    pub result: Option<CommandResult>,
+}
+
+impl ClientSession {
+    /// Returns true if the request was already applied as part of this session.
+    pub fn has_applied(&self, request_id: RequestId) -> bool {
+        request_id <= self.request_id
+    }
+
+    /// Returns the result of the latest request, to answer a retry of it with.
+    pub fn result(&self) -> CommandResult {
+        self.result
+            .clone()
+            .unwrap_or(CommandResult::Written(self.log_idx))
+    }
+
+    /// Adds `offset` to the log indexes of a session from a snapshot, see
+    /// [`CommandResult::offset`].
+    pub fn offset(&mut self, offset: u64) {
+        self.log_idx += offset;
+        if let Some(result) = &mut self.result {
+            result.offset(offset);
+        }
+    }
+}
+
+/// A configuration of the cluster, which runs an OmniPaxos instance of its own. Its log starts
+/// after the stop sign that ended the log of the previous configuration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Configuration {
+    pub id: u32,
+    pub nodes: Vec<u64>,
+    /// Number of log entries decided in the previous configurations, including their stop signs.
+    /// The entries of this configuration are applied at their index plus this offset, so that
+    /// versions and sessions keep increasing across configurations.
+    pub log_offset: u64,
+}
+
+/// Marks a command that reads a key whose value before the snapshot is unknown.
+struct Unknown;
+
+/// A namespace other than the default one, as of the end of a snapshot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamespaceSnapshot {
+    pub compaction: Compaction,
+    /// Versions are relative to the first entry covered by the snapshot.
+    #[serde(with = "byte_keys")]
+    pub keys: HashMap<Key, VersionedValue>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KVSnapshot {
-    snapshotted: HashMap<String, String>,
-    deleted_keys: Vec<String>,
-}
-
-impl Snapshot<KVCommand> for KVSnapshot {
-    fn create(entries: &[KVCommand]) -> Self {
-        let mut snapshotted = HashMap::new();
-        let mut deleted_keys: Vec<String> = Vec::new();
-        for e in entries {
-            match e {
-                KVCommand::Put(KeyValue { key, value }) => {
-                    snapshotted.insert(key.clone(), value.clone());
+    /// The keys of the default namespace. Versions are relative to the first entry covered by
+    /// this snapshot.
+    #[serde(with = "byte_keys")]
+    pub snapshotted: HashMap<Key, VersionedValue>,
+    pub deleted_keys: Vec<Key>,
+    /// The other namespaces that exist after the entries covered by a snapshot that starts at the
+    /// beginning of the log. Otherwise unknown, and commands on them are pending.
+    #[serde(default)]
+    pub namespaces: HashMap<Namespace, NamespaceSnapshot>,
+    /// The summed up deltas of the increments of keys whose value before this snapshot is
+    /// unknown, and the version of their last increment.
+    #[serde(default, with = "byte_keys")]
+    pub increments: HashMap<Key, (i64, u64)>,
+    /// The clients whose latest request is one of `increments`, with its key and the sum of the
+    /// deltas up to and including it. Their results are known once the value of the key is.
+    #[serde(default)]
+    pub increment_results: HashMap<ClientId, (Key, i64)>,
+    /// Entries, with their index relative to the first entry covered by this snapshot, that read
+    /// values from before it. They are applied once the snapshot is merged into the preceding one.
+    pub pending: Vec<(u64, Command)>,
+    /// Session `log_idx`s are relative to the first entry covered by this snapshot.
+    pub sessions: HashMap<ClientId, ClientSession>,
+    /// The entries covered by a snapshot that has not been merged yet. An entry that it folded in
+    /// may be the retry of a request from before the snapshot, which only shows once it is
+    /// merged, and then the entries are applied again one by one instead.
+    #[serde(default)]
+    pub entries: Vec<Command>,
+    /// Number of log entries covered by this snapshot.
+    pub num_entries: u64,
+    /// The replicated clock after the entries covered by a snapshot that starts at the beginning
+    /// of the log. Otherwise unknown, as `Expire` commands in the snapshot are still pending.
+    #[serde(default)]
+    pub clock: u64,
+}
+
+impl KVSnapshot {
+    /// Applies the pending entries of a snapshot that covers the log from its beginning, where a
+    /// key that is not in the snapshot does not exist.
+    pub fn resolve_pending(&mut self) {
+        let increments = std::mem::take(&mut self.increments);
+        let results = std::mem::take(&mut self.increment_results);
+        self.apply_increments(increments, results, 0);
+        for (log_idx, command) in std::mem::take(&mut self.pending) {
+            // cannot fail since every key is known
+            let _ = self.apply(&command, log_idx, true);
+        }
+        // every request from before the snapshot is known
+        self.entries.clear();
+    }
+
+    /// Folds the command at `log_idx` into the snapshot. Fails without changing anything if the
+    /// command reads a key that is not in the snapshot, unless `from_start` is set. A batch may
+    /// fail in part, and the commands of it that were folded are skipped by their sessions when
+    /// it is applied again.
+    fn apply(&mut self, command: &Command, log_idx: u64, from_start: bool) -> Result<(), Unknown> {
+        if let KVCommand::Batch(commands) = &command.kv_cmd {
+            for command in commands {
+                self.apply(command, log_idx, from_start)?;
+            }
+            return Ok(());
+        }
+        if command.kv_cmd.is_read() {
+            // reads do not change the state or advance the client's session
+            return Ok(());
+        }
+        if let Some(session) = self.sessions.get(&command.client_id) {
+            if session.has_applied(command.request_id) {
+                // retry of a command in this snapshot
+                return Ok(());
+            }
+        }
+        let namespaced = command.namespace != DEFAULT_NAMESPACE
+            || matches!(
+                command.kv_cmd,
+                KVCommand::CreateNamespace { .. } | KVCommand::DropNamespace(_)
+            );
+        // the same results as the database gives
+        let (writes, result) = match command.kv_cmd {
+            // the namespaces before the snapshot are unknown
+            _ if namespaced && !from_start => return Err(Unknown),
+            // expires keys from before the snapshot
+            KVCommand::Expire { .. } if !from_start => return Err(Unknown),
+            KVCommand::Expire { now } => {
+                self.clock = self.clock.max(now);
+                let clock = self.clock;
+                self.snapshotted.retain(|_, value| !value.is_expired(clock));
+                for namespace in self.namespaces.values_mut() {
+                    namespace.keys.retain(|_, value| !value.is_expired(clock));
+                }
+                (vec![], Some(CommandResult::Written(log_idx)))
+            }
+            KVCommand::CreateNamespace {
+                ref name,
+                compaction,
+            } => {
+                let result = match check_namespace_name(name) {
+                    Err(reason) => CommandResult::Invalid(reason),
+                    Ok(()) if self.namespaces.contains_key(name) => {
+                        CommandResult::Invalid(format!("namespace {} already exists", name))
+                    }
+                    Ok(()) => {
+                        let namespace = NamespaceSnapshot {
+                            compaction,
+                            keys: HashMap::new(),
+                        };
+                        self.namespaces.insert(name.clone(), namespace);
+                        CommandResult::Written(log_idx)
+                    }
+                };
+                (vec![], Some(result))
+            }
+            KVCommand::DropNamespace(ref name) => {
+                let result = match check_namespace_name(name) {
+                    Err(reason) => CommandResult::Invalid(reason),
+                    Ok(()) => match self.namespaces.remove(name) {
+                        Some(_) => CommandResult::Written(log_idx),
+                        None => no_such_namespace(name),
+                    },
+                };
+                (vec![], Some(result))
+            }
+            _ if namespaced && !self.namespaces.contains_key(&command.namespace) => {
+                (vec![], Some(no_such_namespace(&command.namespace)))
+            }
+            // sum up the increments of a key until its value is known
+            KVCommand::Increment { ref key, delta }
+                if !from_start && self.lookup(DEFAULT_NAMESPACE, key, false).is_err() =>
+            {
+                let increment = self.increments.entry(key.clone()).or_insert((0, 0));
+                *increment = (increment.0.wrapping_add(delta), log_idx);
+                let result = (key.clone(), increment.0);
+                self.increment_results.insert(command.client_id, result);
+                (vec![], None)
+            }
+            _ => {
+                let clock = || if from_start { Ok(self.clock) } else { Err(Unknown) };
+                let get = |key: &[u8]| self.lookup(&command.namespace, key, from_start);
+                let (writes, result) = command.kv_cmd.execute(log_idx, get, clock)?;
+                (writes, Some(result))
+            }
+        };
+        match self.namespaces.get_mut(&command.namespace) {
+            // only applied from the start, where no deleted keys are tracked
+            Some(namespace) => {
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => namespace.keys.insert(key, value),
+                        None => namespace.keys.remove(&key),
+                    };
+                }
+            }
+            None => {
+                for (key, value) in writes {
+                    self.increments.remove(&key);
+                    match value {
+                        Some(value) => {
+                            self.deleted_keys.retain(|k| *k != key);
+                            self.snapshotted.insert(key, value);
+                        }
+                        None => {
+                            self.snapshotted.remove(&key);
+                            if !self.deleted_keys.contains(&key) {
+                                self.deleted_keys.push(key);
+                            }
+                        }
+                    }
                 }
-                KVCommand::Delete(key) => {
-                    if snapshotted.remove(key).is_none() {
-                        // key was not in the snapshot
-                        deleted_keys.push(key.clone());
+            }
+        }
+        if result.is_some() {
+            self.increment_results.remove(&command.client_id);
+        }
+        let session = ClientSession {
+            request_id: command.request_id,
+            log_idx,
+            result,
+        };
+        self.sessions.insert(command.client_id, session);
+        Ok(())
+    }
+
+    /// Applies summed up increments, with versions offset by `offset`, to a snapshot that covers
+    /// the log from its beginning. Equivalent to applying them one by one, as they all fail if
+    /// the value is not an integer. The sessions of the increments in `results` must be in the
+    /// snapshot already, and get their results.
+    fn apply_increments(
+        &mut self,
+        increments: HashMap<Key, (i64, u64)>,
+        results: HashMap<ClientId, (Key, i64)>,
+        offset: u64,
+    ) {
+        for (client_id, (key, delta)) in results {
+            let increment = KVCommand::Increment { key, delta };
+            let get = |key: &[u8]| self.lookup(DEFAULT_NAMESPACE, key, true);
+            if let Some(session) = self.sessions.get(&client_id) {
+                let log_idx = session.log_idx;
+                if let Ok((_, result)) = increment.execute(log_idx, get, || Ok(self.clock)) {
+                    if let Some(session) = self.sessions.get_mut(&client_id) {
+                        session.result = Some(result);
                     }
                 }
-                KVCommand::Get(_) => (),
             }
         }
-        // remove keys that were put back
-        deleted_keys.retain(|k| !snapshotted.contains_key(k));
-        Self {
-            snapshotted,
-            deleted_keys,
+        for (key, (delta, version)) in increments {
+            let increment = KVCommand::Increment { key, delta };
+            let get = |key: &[u8]| self.lookup(DEFAULT_NAMESPACE, key, true);
+            if let Ok((writes, _)) = increment.execute(version + offset, get, || Ok(self.clock)) {
+                for (key, value) in writes {
+                    self.deleted_keys.retain(|k| *k != key);
+                    match value {
+                        Some(value) => self.snapshotted.insert(key, value),
+                        None => self.snapshotted.remove(&key),
+                    };
+                }
+            }
+        }
+    }
+
+    fn lookup(
+        &self,
+        namespace: &str,
+        key: &[u8],
+        from_start: bool,
+    ) -> Result<Option<VersionedValue>, Unknown> {
+        let value = match self.namespaces.get(namespace) {
+            Some(namespace) => namespace.keys.get(key),
+            None => self.snapshotted.get(key),
+        };
+        match value {
+            // keys with a TTL are only written once the clock is known
+            Some(value) if value.is_expired(self.clock) => Ok(None),
+            Some(value) => Ok(Some(value.clone())),
+            None if from_start || self.deleted_keys.iter().any(|k| *k == key) => Ok(None),
+            None => Err(Unknown),
+        }
+    }
+}
+
+impl Snapshot<Command> for KVSnapshot {
+    fn create(entries: &[Command]) -> Self {
+        let mut snapshot = Self {
+            snapshotted: HashMap::new(),
+            deleted_keys: Vec::new(),
+            namespaces: HashMap::new(),
+            increments: HashMap::new(),
+            increment_results: HashMap::new(),
+            pending: Vec::new(),
+            sessions: HashMap::new(),
+            entries: entries.to_vec(),
+            num_entries: entries.len() as u64,
+            clock: 0,
+        };
+        for (log_idx, e) in entries.iter().enumerate() {
+            if e.kv_cmd.is_read() {
+                continue;
+            }
+            let log_idx = log_idx as u64;
+            // once an entry is pending, the ones after it may depend on its outcome
+            if !snapshot.pending.is_empty() || snapshot.apply(e, log_idx, false).is_err() {
+                snapshot.pending.push((log_idx, e.clone()));
+            }
         }
+        snapshot
     }
 
     fn merge(&mut self, delta: Self) {
-        for (k, v) in delta.snapshotted {
+        self.resolve_pending();
+        // the delta starts right after the entries covered by this snapshot
+        let offset = self.num_entries;
+        self.num_entries += delta.num_entries;
+        let retried = delta.entries.iter().flat_map(Command::commands).any(|command| {
+            let session = self.sessions.get(&command.client_id);
+            matches!(session, Some(session) if session.has_applied(command.request_id))
+        });
+        if retried {
+            for (log_idx, command) in delta.entries.iter().enumerate() {
+                let _ = self.apply(command, log_idx as u64 + offset, true);
+            }
+            return;
+        }
+        for (k, mut v) in delta.snapshotted {
+            v.version += offset;
             self.snapshotted.insert(k, v);
         }
         for k in delta.deleted_keys {
             self.snapshotted.remove(&k);
         }
+        for (client_id, mut session) in delta.sessions {
+            session.offset(offset);
+            self.sessions.insert(client_id, session);
+        }
+        self.apply_increments(delta.increments, delta.increment_results, offset);
+        // including every command of the delta on a namespace other than the default one
+        for (log_idx, command) in delta.pending {
+            let _ = self.apply(&command, log_idx + offset, true);
+        }
         self.deleted_keys.clear();
     }
 
@@ -65,3 +817,195 @@ impl Snapshot<KVCommand> for KVSnapshot {
         true
     }
 }
+
+/// Serializes a map with byte keys as a list of pairs, since JSON objects only have string keys.
+mod byte_keys {
+    use super::Key;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<V: Serialize, S: Serializer>(
+        map: &HashMap<Key, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<Key, V>, D::Error> {
+        let pairs = Vec::<(Key, V)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(client_id: ClientId, request_id: RequestId, kv_cmd: KVCommand) -> Command {
+        Command {
+            client_id,
+            request_id,
+            coordinator_id: 1,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            kv_cmd,
+        }
+    }
+
+    fn put(key: &str, value: &str) -> KVCommand {
+        KVCommand::Put {
+            key: key.into(),
+            value: value.into(),
+            if_version: None,
+            ttl_ms: None,
+        }
+    }
+
+    fn incr(key: &str, delta: i64) -> KVCommand {
+        KVCommand::Increment {
+            key: key.into(),
+            delta,
+        }
+    }
+
+    /// Merges the snapshots of `a` and `b`, and checks that the result is the same as that of a
+    /// snapshot of both resolved from the start of the log.
+    fn merge(a: &[Command], b: &[Command]) -> KVSnapshot {
+        let mut merged = KVSnapshot::create(a);
+        merged.merge(KVSnapshot::create(b));
+        let mut whole = KVSnapshot::create(&[a, b].concat());
+        whole.resolve_pending();
+        assert_eq!(merged.snapshotted, whole.snapshotted);
+        assert_eq!(merged.sessions, whole.sessions);
+        assert_eq!(merged.num_entries, whole.num_entries);
+        assert!(merged.pending.is_empty());
+        merged
+    }
+
+    fn value(snapshot: &KVSnapshot, key: &str) -> (String, u64) {
+        let value = &snapshot.snapshotted[key.as_bytes()];
+        (String::from_utf8(value.value.clone()).unwrap(), value.version)
+    }
+
+    fn result(snapshot: &KVSnapshot, client_id: ClientId) -> CommandResult {
+        snapshot.sessions[&client_id].result()
+    }
+
+    #[test]
+    fn increments_of_an_unknown_key_are_collapsed() {
+        let b = [command(2, 1, incr("k", 2)), command(1, 2, incr("k", 3))];
+        let delta = KVSnapshot::create(&b);
+        assert_eq!(delta.increments[b"k".as_slice()], (5, 1));
+        assert!(delta.pending.is_empty());
+
+        let merged = merge(&[command(1, 1, incr("k", 1))], &b);
+        assert_eq!(value(&merged, "k"), ("6".to_string(), 2));
+        let updated = |log_idx, value: &str| CommandResult::Updated {
+            log_idx,
+            value: value.into(),
+        };
+        assert_eq!(result(&merged, 1), updated(2, "6"));
+        assert_eq!(result(&merged, 2), updated(1, "3"));
+    }
+
+    #[test]
+    fn increments_of_a_non_integer_fail() {
+        let a = [command(1, 1, put("k", "x"))];
+        let merged = merge(&a, &[command(2, 1, incr("k", 1))]);
+        assert_eq!(value(&merged, "k"), ("x".to_string(), 0));
+        assert!(matches!(result(&merged, 2), CommandResult::Invalid(_)));
+    }
+
+    #[test]
+    fn increments_followed_by_a_put() {
+        let a = [command(1, 1, incr("k", 1))];
+        let b = [
+            command(1, 2, incr("k", 2)),
+            command(2, 1, put("k", "10")),
+            command(1, 3, incr("k", 3)),
+        ];
+        let merged = merge(&a, &b);
+        assert_eq!(value(&merged, "k"), ("13".to_string(), 3));
+
+        let a = [command(1, 1, put("k", "10"))];
+        let b = [command(1, 2, incr("k", 1)), command(2, 1, incr("k", 2))];
+        let merged = merge(&a, &b);
+        assert_eq!(value(&merged, "k"), ("13".to_string(), 2));
+    }
+
+    #[test]
+    fn reads_of_an_unknown_key_are_pending() {
+        let a = [command(1, 1, put("k", "v"))];
+        let cas = KVCommand::CompareAndSwap {
+            key: "k".into(),
+            expected: Some("v".into()),
+            new: Some("w".into()),
+        };
+        let txn = KVCommand::Txn {
+            guards: vec![Guard::Absent("j".into())],
+            ops: vec![TxnOp::Put(KeyValue {
+                key: "j".into(),
+                value: "x".into(),
+            })],
+        };
+        let b = [
+            command(2, 1, cas),
+            command(3, 1, txn),
+            command(1, 2, put("l", "y")),
+        ];
+        let delta = KVSnapshot::create(&b);
+        // the entries after a pending one may depend on it
+        assert_eq!(delta.pending.len(), 3);
+
+        let merged = merge(&a, &b);
+        assert_eq!(value(&merged, "k"), ("w".to_string(), 1));
+        assert_eq!(value(&merged, "j"), ("x".to_string(), 2));
+        assert_eq!(value(&merged, "l"), ("y".to_string(), 3));
+        let txn = CommandResult::Txn {
+            log_idx: 2,
+            failed_guard: None,
+        };
+        assert_eq!(result(&merged, 3), txn);
+    }
+
+    #[test]
+    fn retries_are_applied_once_across_a_merge() {
+        let a = [
+            command(2, 1, put("k", "0")),
+            command(1, 1, incr("k", 1)),
+            command(1, 2, put("j", "x")),
+        ];
+        // a retry of the increment, and one of an older request of a pipelining client
+        let b = [
+            command(1, 1, incr("k", 1)),
+            command(1, 2, put("j", "y")),
+            command(1, 3, incr("k", 1)),
+        ];
+        let merged = merge(&a, &b);
+        assert_eq!(value(&merged, "k"), ("2".to_string(), 5));
+        assert_eq!(value(&merged, "j"), ("x".to_string(), 2));
+    }
+
+    #[test]
+    fn writes_of_the_same_key() {
+        let txn = KVCommand::Txn {
+            guards: vec![Guard::Absent("j".into())],
+            ops: vec![TxnOp::Delete("k".into())],
+        };
+        let txn = command(1, 1, txn);
+        assert!(txn.writes_same_key(&command(2, 1, incr("k", 1))));
+        // guards only read their keys
+        assert!(!txn.writes_same_key(&command(2, 1, put("j", "x"))));
+        let mut other = command(2, 1, put("k", "x"));
+        other.namespace = "other".to_string();
+        assert!(!txn.writes_same_key(&other));
+    }
+
+    #[test]
+    fn retries_are_applied_once_within_a_snapshot() {
+        let b = [command(1, 1, incr("k", 1)), command(1, 1, incr("k", 1))];
+        let merged = merge(&[command(2, 1, put("k", "0"))], &b);
+        assert_eq!(value(&merged, "k"), ("1".to_string(), 1));
+    }
+}
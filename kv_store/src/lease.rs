@@ -0,0 +1,377 @@
+use crate::server::Staleness;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct LeaseConfig {
+    /// How long a lease is held after a quorum acknowledged it. Must be shorter than the election
+    /// timeout, so that the followers cannot elect a new leader while the old one still holds the lease.
+    pub duration: Duration,
+    /// Upper bound on how much the clocks of two servers may drift apart during `duration`.
+    pub max_clock_drift: Duration,
+}
+
+/// A time-bounded lease that allows the leader to serve reads from its local database. The
+/// leader renews it in rounds: a round is granted once a quorum, including the leader itself,
+/// acknowledged that it still follows the leader.
+pub struct Lease {
+    config: LeaseConfig,
+    quorum: usize,
+    round: u64,
+    round_started: Instant,
+    acks: HashSet<u64>,
+    /// Highest decided index reported in the acks of the current round.
+    acked_decided_idx: u64,
+    valid_until: Option<Instant>,
+    /// The leader must have applied the log up to here before serving reads under the lease, so
+    /// that it does not miss writes decided by a previous leader.
+    required_applied_idx: u64,
+    /// Index of the first entry decided in the term of this leader. A write that a previous
+    /// leader decided may not be known as decided by any follower yet, but it precedes this entry
+    /// in the log, so the leader serves no reads under the lease before it applied this one.
+    term_entry_idx: Option<u64>,
+}
+
+impl Lease {
+    pub fn new(config: LeaseConfig, num_nodes: usize) -> Self {
+        Self {
+            config,
+            quorum: num_nodes / 2 + 1,
+            round: 0,
+            round_started: Instant::now(),
+            acks: HashSet::new(),
+            acked_decided_idx: 0,
+            valid_until: None,
+            required_applied_idx: 0,
+            term_entry_idx: None,
+        }
+    }
+
+    /// Starts the term of a newly elected leader, which has to decide an entry of its own before
+    /// it holds the lease.
+    pub fn start_term(&mut self) {
+        self.term_entry_idx = None;
+        self.revoke();
+    }
+
+    /// Records that an entry proposed in the current term was decided at `idx`.
+    pub fn handle_term_entry(&mut self, idx: u64) {
+        if self.term_entry_idx.is_none() {
+            self.term_entry_idx = Some(idx);
+        }
+    }
+
+    /// Starts a new renewal round and returns its number.
+    pub fn start_round(&mut self, decided_idx: u64) -> u64 {
+        self.round += 1;
+        self.round_started = Instant::now();
+        self.acks.clear();
+        self.acked_decided_idx = decided_idx;
+        self.try_renew();
+        self.round
+    }
+
+    pub fn handle_ack(&mut self, from: u64, round: u64, decided_idx: u64) {
+        if round == self.round && self.acks.insert(from) {
+            self.acked_decided_idx = self.acked_decided_idx.max(decided_idx);
+            self.try_renew();
+        }
+    }
+
+    /// Returns true if the lease is held and the leader has applied everything decided before it.
+    pub fn is_valid(&self, applied_idx: u64) -> bool {
+        match (self.valid_until, self.term_entry_idx) {
+            (Some(valid_until), Some(term_entry_idx)) => {
+                Instant::now() < valid_until
+                    && applied_idx >= self.required_applied_idx.max(term_entry_idx)
+            }
+            _ => false,
+        }
+    }
+
+    /// Gives up the lease, e.g. when the server is no longer the leader.
+    pub fn revoke(&mut self) {
+        self.valid_until = None;
+    }
+
+    /// Adapts the quorum to a new configuration of the cluster, in which the lease has to be
+    /// acquired anew.
+    pub fn set_num_nodes(&mut self, num_nodes: usize) {
+        self.quorum = num_nodes / 2 + 1;
+        // acks of the current round come from the previous configuration
+        self.round += 1;
+        self.acks.clear();
+        // the configuration starts with a term of its own
+        self.start_term();
+    }
+
+    fn try_renew(&mut self) {
+        // the leader counts towards the quorum
+        if self.acks.len() + 1 >= self.quorum {
+            // The lease is measured from when the round started, which is no later than when the
+            // followers acknowledged it.
+            let valid_for = self
+                .config
+                .duration
+                .saturating_sub(self.config.max_clock_drift);
+            self.valid_until = Some(self.round_started + valid_for);
+            self.required_applied_idx = self.acked_decided_idx;
+        }
+    }
+}
+
+/// The lease that a follower granted to a leader by acknowledging one of its rounds. Until the
+/// lease has expired the follower supports no other leader: a server that cannot reach the leader
+/// may still reach the follower, and would otherwise get it to promise a higher ballot and decide
+/// writes that the leader does not see while it serves reads under its lease.
+pub struct LeaseGrant {
+    duration: Duration,
+    /// The leader that the lease was granted to, and when the grant expires.
+    granted: Option<(u64, Instant)>,
+}
+
+impl LeaseGrant {
+    pub fn new(config: LeaseConfig) -> Self {
+        Self {
+            duration: config.duration,
+            granted: None,
+        }
+    }
+
+    /// Records that a round of `leader` was acknowledged. The leader measures its lease from
+    /// before the acknowledgement and subtracts the clock drift, so it expires first.
+    pub fn grant(&mut self, leader: u64) {
+        self.granted = Some((leader, Instant::now() + self.duration));
+    }
+
+    /// Returns true if the OmniPaxos messages of `pid` may be handled, which is the case unless
+    /// a lease granted to another server may still be held.
+    pub fn allows(&self, pid: u64) -> bool {
+        match self.granted {
+            Some((leader, until)) => leader == pid || Instant::now() >= until,
+            None => true,
+        }
+    }
+}
+
+/// Tracks how far this server is behind the leader, from the decided indexes that the leader
+/// sends along with its lease requests.
+#[derive(Default)]
+pub struct LeaderProgress {
+    /// Decided indexes reported by the leader and when they were received, oldest first.
+    reports: VecDeque<(Instant, u64)>,
+    /// When the leader reported the newest decided index that we have applied.
+    caught_up_at: Option<Instant>,
+    leader_decided_idx: u64,
+}
+
+impl LeaderProgress {
+    pub fn handle_report(&mut self, decided_idx: u64) {
+        let now = Instant::now();
+        match self.reports.back_mut() {
+            // the leader has not decided anything new, the report is just more recent
+            Some((received, idx)) if *idx == decided_idx => *received = now,
+            _ => self.reports.push_back((now, decided_idx)),
+        }
+        self.leader_decided_idx = self.leader_decided_idx.max(decided_idx);
+    }
+
+    /// Returns true if a read served at `applied_idx` is within `max` of the leader. A lag in
+    /// entries is measured against the last decided index heard from the leader.
+    pub fn is_within(&mut self, max: Staleness, applied_idx: u64) -> bool {
+        while let Some((received, idx)) = self.reports.front() {
+            if *idx > applied_idx {
+                break;
+            }
+            self.caught_up_at = Some(*received);
+            self.reports.pop_front();
+        }
+        match max {
+            Staleness::Entries(max_lag) => {
+                let heard_from_leader = self.caught_up_at.is_some() || !self.reports.is_empty();
+                heard_from_leader && self.leader_decided_idx.saturating_sub(applied_idx) <= max_lag
+            }
+            Staleness::Millis(max_ms) => match self.caught_up_at {
+                Some(caught_up_at) => caught_up_at.elapsed() <= Duration::from_millis(max_ms),
+                None => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const CONFIG: LeaseConfig = LeaseConfig {
+        duration: Duration::from_millis(40),
+        max_clock_drift: Duration::from_millis(5),
+    };
+
+    /// A lease of a leader of `num_nodes` that has applied an entry of its term at index 1.
+    fn leader(num_nodes: usize) -> Lease {
+        let mut lease = Lease::new(CONFIG, num_nodes);
+        lease.start_term();
+        lease.handle_term_entry(1);
+        lease
+    }
+
+    #[test]
+    fn granted_once_a_quorum_acked() {
+        let mut lease = leader(5);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        assert!(!lease.is_valid(1));
+        // the same follower counts once
+        lease.handle_ack(2, round, 1);
+        assert!(!lease.is_valid(1));
+        lease.handle_ack(3, round, 1);
+        assert!(lease.is_valid(1));
+    }
+
+    #[test]
+    fn single_node_needs_no_acks() {
+        let mut lease = leader(1);
+        lease.start_round(1);
+        assert!(lease.is_valid(1));
+    }
+
+    #[test]
+    fn acks_of_an_old_round_are_ignored() {
+        let mut lease = leader(3);
+        let old = lease.start_round(1);
+        lease.start_round(1);
+        lease.handle_ack(2, old, 1);
+        assert!(!lease.is_valid(1));
+    }
+
+    #[test]
+    fn expires_early_by_the_clock_drift() {
+        let mut lease = leader(3);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        assert!(lease.is_valid(1));
+        assert!(lease.valid_until.unwrap() <= lease.round_started + Duration::from_millis(35));
+        sleep(Duration::from_millis(40));
+        assert!(!lease.is_valid(1));
+    }
+
+    #[test]
+    fn revoke() {
+        let mut lease = leader(3);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        lease.revoke();
+        assert!(!lease.is_valid(1));
+    }
+
+    #[test]
+    fn waits_for_what_the_followers_decided() {
+        let mut lease = leader(3);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 4);
+        assert!(!lease.is_valid(3));
+        assert!(lease.is_valid(4));
+    }
+
+    #[test]
+    fn waits_for_an_entry_of_the_term() {
+        let mut lease = Lease::new(CONFIG, 3);
+        lease.start_term();
+        let round = lease.start_round(2);
+        lease.handle_ack(2, round, 2);
+        // a quorum acked, but an entry decided by the previous leader may be unknown to both
+        assert!(!lease.is_valid(2));
+        lease.handle_term_entry(5);
+        assert!(!lease.is_valid(4));
+        assert!(lease.is_valid(5));
+        // a later entry of the term does not move the requirement
+        lease.handle_term_entry(7);
+        assert!(lease.is_valid(5));
+    }
+
+    #[test]
+    fn a_new_term_starts_without_the_lease() {
+        let mut lease = leader(3);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        lease.start_term();
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        assert!(!lease.is_valid(1));
+    }
+
+    #[test]
+    fn set_num_nodes() {
+        let mut lease = leader(3);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        lease.set_num_nodes(5);
+        assert!(!lease.is_valid(1));
+        // acks of the round from before the reconfiguration no longer count
+        lease.handle_ack(3, round, 1);
+        assert!(!lease.is_valid(1));
+        lease.handle_term_entry(1);
+        let round = lease.start_round(1);
+        lease.handle_ack(2, round, 1);
+        assert!(!lease.is_valid(1));
+        lease.handle_ack(3, round, 1);
+        assert!(lease.is_valid(1));
+    }
+
+    #[test]
+    fn grant_holds_off_other_leaders() {
+        let mut grant = LeaseGrant::new(CONFIG);
+        assert!(grant.allows(2));
+        grant.grant(1);
+        assert!(grant.allows(1));
+        assert!(!grant.allows(2));
+        sleep(CONFIG.duration);
+        assert!(grant.allows(2));
+    }
+
+    /// Leader 1 holds the lease of a cluster of 3 with the ack of follower 3. Server 2 cannot
+    /// reach the leader and is elected by follower 3, which only supports it once the lease that
+    /// it granted has expired, so the old leader serves no reads after the new one may decide
+    /// writes.
+    #[test]
+    fn no_stale_reads_after_a_partial_partition() {
+        let mut old_leader = leader(3);
+        let mut follower = LeaseGrant::new(CONFIG);
+        let round = old_leader.start_round(1);
+        follower.grant(1);
+        old_leader.handle_ack(3, round, 1);
+        assert!(old_leader.is_valid(1));
+        // the follower switched to server 2, and acks none of the old leader's rounds anymore
+        let mut elapsed = Duration::ZERO;
+        while !follower.allows(2) {
+            assert!(elapsed < 2 * CONFIG.duration);
+            old_leader.start_round(1);
+            sleep(Duration::from_millis(1));
+            elapsed += Duration::from_millis(1);
+        }
+        // the new leader gets the promise of the follower, and may decide writes from now on
+        assert!(!old_leader.is_valid(1));
+    }
+
+    #[test]
+    fn follower_within_entries_of_the_leader() {
+        let mut progress = LeaderProgress::default();
+        // nothing heard from the leader yet
+        assert!(!progress.is_within(Staleness::Entries(10), 0));
+        progress.handle_report(5);
+        assert!(progress.is_within(Staleness::Entries(2), 3));
+        assert!(!progress.is_within(Staleness::Entries(1), 3));
+    }
+
+    #[test]
+    fn follower_within_millis_of_the_leader() {
+        let mut progress = LeaderProgress::default();
+        progress.handle_report(5);
+        assert!(!progress.is_within(Staleness::Millis(1000), 4));
+        assert!(progress.is_within(Staleness::Millis(1000), 5));
+        sleep(Duration::from_millis(20));
+        assert!(!progress.is_within(Staleness::Millis(10), 5));
+    }
+}
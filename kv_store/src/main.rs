@@ -1,62 +1,93 @@
-use crate::kv::KVCommand;
-use crate::server::Server;
+use crate::config::Config;
+use crate::kv::{Command, Configuration};
+use crate::lease::{LeaderProgress, Lease, LeaseGrant};
+use crate::server::{Server, StateFetch};
 use omnipaxos::*;
-use omnipaxos_storage::memory_storage::MemoryStorage;
-use std::env;
+use omnipaxos_storage::persistent_storage::{PersistentStorage, PersistentStorageConfig};
 use tokio;
 
-#[macro_use]
-extern crate lazy_static;
-
+mod config;
 mod database;
 mod kv;
+mod lease;
 mod network;
 mod server;
 
-lazy_static! {
-    pub static ref NODES: Vec<u64> = if let Ok(var) = env::var("NODES") {
-        serde_json::from_str::<Vec<u64>>(&var).expect("wrong config format")
-    } else {
-        vec![]
-    };
-    pub static ref PID: u64 = if let Ok(var) = env::var("PID") {
-        let x = var.parse().expect("PIDs must be u64");
-        if x == 0 {
-            panic!("PIDs cannot be 0")
-        } else {
-            x
-        }
-    } else {
-        panic!("missing PID")
-    };
-}
-
-type OmniPaxosKV = OmniPaxos<KVCommand, MemoryStorage<KVCommand>>;
+type OmniPaxosKV = OmniPaxos<Command, PersistentStorage<Command>>;
 
-#[tokio::main]
-async fn main() {
+/// Builds the OmniPaxos instance of a configuration, which keeps its log in a directory of its
+/// own.
+pub(crate) fn build_omni_paxos(config: &Config, configuration: &Configuration) -> OmniPaxosKV {
     let server_config = ServerConfig {
-        pid: *PID,
-        election_tick_timeout: 5,
+        pid: config.pid,
+        election_tick_timeout: config.election_tick_timeout,
         ..Default::default()
     };
     let cluster_config = ClusterConfig {
-        configuration_id: 1,
-        nodes: (*NODES).clone(),
+        configuration_id: configuration.id,
+        nodes: configuration.nodes.clone(),
         ..Default::default()
     };
     let op_config = OmniPaxosConfig {
         server_config,
         cluster_config,
     };
-    let omni_paxos = op_config
-        .build(MemoryStorage::default())
-        .expect("failed to build OmniPaxos");
+    // The OmniPaxos log, promised ballot and accepted round are kept on disk so that a restarted
+    // node rejoins with its log intact.
+    let mut storage_config = PersistentStorageConfig::default();
+    storage_config.set_path(config.storage_path(configuration.id));
+    op_config
+        .build(PersistentStorage::open(storage_config))
+        .expect("failed to build OmniPaxos")
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    });
+    let database = database::Database::new(&config.db_path())
+        .expect("failed to open database");
+    let stored_configuration = database
+        .get_configuration()
+        .expect("failed to read configuration");
+    // A replica that is added to a running cluster waits for the state of the replicas already in
+    // the configuration, and learns where the log of the configuration starts from them.
+    let awaiting_state = stored_configuration.is_none() && config.configuration_id > 1;
+    let configuration = stored_configuration.unwrap_or_else(|| Configuration {
+        id: config.configuration_id,
+        nodes: config.nodes.clone(),
+        log_offset: 0,
+    });
+    let omni_paxos = build_omni_paxos(&config, &configuration);
+    // resume from where the state machine left off before a restart
+    let applied_idx = database
+        .get_applied_idx()
+        .expect("failed to read applied index");
+    let last_decided_idx = applied_idx.saturating_sub(configuration.log_offset);
+    let network = network::Network::new(&config, &configuration.nodes)
+        .await
+        .expect("failed to listen for peers");
     let mut server = Server {
         omni_paxos,
-        network: network::Network::new().await,
-        database: database::Database::new(format!("db_{}", *PID).as_str()),
-        last_decided_idx: 0,
+        network,
+        database,
+        last_decided_idx,
+        lease: Lease::new(config.lease_config(), configuration.nodes.len()),
+        lease_grant: LeaseGrant::new(config.lease_config()),
+        leader_progress: LeaderProgress::default(),
+        leader_term: None,
+        configuration,
+        awaiting_state,
+        missed_configuration: None,
+        state_fetch: StateFetch::default(),
+        pending_reconfiguration: None,
+        config,
     };
-    server.run().await;
+    if let Err(e) = server.run().await {
+        // Nothing of the entry that failed was written, so it is applied again after a restart.
+        eprintln!("Halting, failed to apply the log: {}", e);
+        std::process::exit(1);
+    }
 }
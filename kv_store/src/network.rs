@@ -2,49 +2,540 @@ use omnipaxos::messages::Message as OPMessage;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{tcp, TcpStream},
-    sync::Mutex,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp, TcpListener, TcpStream},
+    sync::{mpsc, watch, Mutex},
+    time::{sleep, timeout},
 };
 
-use crate::{kv::KVCommand, server::APIResponse, NODES, PID as MY_PID};
+use crate::{
+    config::Config,
+    kv::{ClientId, Command, Configuration, RequestId},
+    server::{APIRequest, APIResponse},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum Message {
-    OmniPaxosMsg(OPMessage<KVCommand>),
-    APIRequest(KVCommand),
+    /// Tagged with the id of the configuration whose OmniPaxos instance sent it, as the instances
+    /// of different configurations must not receive each other's messages.
+    OmniPaxosMsg(u32, OPMessage<Command>),
+    APIRequest(APIRequest),
     APIResponse(APIResponse),
+    /// Sent by the leader to renew its lease. Also tells the followers up to which index the
+    /// leader has decided.
+    LeaseRequest {
+        from: u64,
+        round: u64,
+        decided_idx: u64,
+    },
+    /// Sent by a follower that still follows the leader that requested the lease.
+    LeaseAck {
+        from: u64,
+        round: u64,
+        decided_idx: u64,
+    },
+    /// Sent by a replica that joins the cluster in the given configuration, or that missed its
+    /// start, for a chunk of the state that the configuration started from.
+    StateRequest {
+        from: u64,
+        configuration_id: u32,
+        chunk: u64,
+    },
+    /// A chunk of the state that `configuration` started from, which is `num_chunks` long.
+    StateTransfer {
+        from: u64,
+        configuration: Configuration,
+        chunk: u64,
+        num_chunks: u64,
+        data: Vec<u8>,
+    },
+    /// Sent instead of a [`Message::StateTransfer`] by a replica that failed to read the chunk.
+    StateUnavailable {
+        from: u64,
+        configuration_id: u32,
+        reason: String,
+    },
+}
+
+/// How long to wait before connecting again after a failed attempt. Doubled after every failed
+/// attempt, up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Number of outgoing messages that may wait to be written to a connection. Further messages are
+/// dropped, as are those sent while the connection is down.
+const OUTGOING_BUFFER: usize = 1024;
+/// How long the other side of an accepted connection has to introduce itself.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Version of the wire protocol, which both sides of a connection send each other first. A
+/// connection speaks the lower of the two versions, and is closed if we do not speak that one.
+const PROTOCOL_VERSION: u8 = 1;
+/// Frames longer than this are taken for a corrupt stream, which closes the connection.
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// How messages are encoded on the wire. Every frame names the codec of its message, so that each
+/// side of a connection may use its own, and the network actor forwards frames without decoding
+/// them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Bincode,
+    /// Larger and slower, but readable when debugging.
+    Json,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Bincode => 1,
+            Codec::Json => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Bincode),
+            2 => Some(Codec::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Where the connections accepted from each peer are handed to its link, in direct mode.
+type Acceptors = Arc<Mutex<HashMap<u64, mpsc::Sender<Connection>>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LinkState {
+    Connected,
+    /// Not connected, and trying to connect with backoff.
+    Reconnecting,
+}
+
+/// A connection of a link, and the pid of the server that dialed it.
+struct Connection {
+    stream: TcpStream,
+    dialer: u64,
+}
+
+/// How a link gets connected.
+enum Connector {
+    /// Dials the network actor at `addr`, which tells the links apart by the address.
+    Proxy { addr: String },
+    /// Dials the peer at `addr` directly and introduces itself as `my_pid`. The connections that
+    /// the peer dials are handed over through `accepted`.
+    Direct {
+        addr: String,
+        my_pid: u64,
+        accepted: mpsc::Receiver<Connection>,
+    },
+}
+
+impl Connector {
+    fn addr(&self) -> &str {
+        match self {
+            Connector::Proxy { addr } | Connector::Direct { addr, .. } => addr,
+        }
+    }
+
+    /// Dials with backoff until connected, or until the peer dialed us in the meantime.
+    async fn connect(&mut self) -> Connection {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self {
+                Connector::Proxy { addr } => {
+                    if let Ok(mut stream) = TcpStream::connect(&*addr).await {
+                        if handshake(&mut stream).await.is_ok() {
+                            return Connection { stream, dialer: 0 };
+                        }
+                    }
+                    sleep(backoff).await;
+                }
+                Connector::Direct {
+                    addr,
+                    my_pid,
+                    accepted,
+                } => {
+                    let dial = async {
+                        match dial(addr, *my_pid).await {
+                            Ok(stream) => Some(stream),
+                            Err(_) => {
+                                sleep(backoff).await;
+                                None
+                            }
+                        }
+                    };
+                    tokio::select! {
+                        Some(connection) = accepted.recv() => return connection,
+                        stream = dial => if let Some(stream) = stream {
+                            return Connection { stream, dialer: *my_pid };
+                        },
+                    }
+                }
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Waits for a connection that the peer dialed and that replaces the current one, which was
+    /// dialed by `dialer`. When both sides dial at the same time, both keep the connection dialed
+    /// by the lower pid. A peer that dials again replaces its previous connection, which it
+    /// has given up on.
+    async fn replacement(&mut self, dialer: u64) -> Connection {
+        if let Connector::Direct { accepted, .. } = self {
+            while let Some(connection) = accepted.recv().await {
+                if connection.dialer <= dialer {
+                    return connection;
+                }
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// Exchanges protocol versions with the other side of a new connection.
+async fn handshake(stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_u8(PROTOCOL_VERSION).await?;
+    let version = stream.read_u8().await?.min(PROTOCOL_VERSION);
+    if version < PROTOCOL_VERSION {
+        let reason = format!("unsupported protocol version {}", version);
+        println!("Closing a connection: {}", reason);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    }
+    Ok(())
+}
+
+/// Connects to a peer directly, and tells it who we are.
+async fn dial(addr: &str, my_pid: u64) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await?;
+    handshake(&mut stream).await?;
+    stream.write_u64(my_pid).await?;
+    Ok(stream)
+}
+
+/// Accepts the connections that other servers dial, and hands each one to the link to the pid
+/// that the server introduces itself as.
+async fn accept_peers(listener: TcpListener, acceptors: Acceptors) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                println!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let acceptors = acceptors.clone();
+        tokio::spawn(async move {
+            let introduction = async {
+                handshake(&mut stream).await?;
+                stream.read_u64().await
+            };
+            let dialer = match timeout(HANDSHAKE_TIMEOUT, introduction).await {
+                Ok(Ok(pid)) => pid,
+                _ => return,
+            };
+            let acceptor = acceptors.lock().await.get(&dialer).cloned();
+            match acceptor {
+                Some(acceptor) => {
+                    let _ = acceptor.send(Connection { stream, dialer }).await;
+                }
+                None => println!("Dropping a connection from unknown peer {}", dialer),
+            }
+        });
+    }
+}
+
+/// A connection to a peer or to the client API, which is re-established whenever it breaks.
+struct Link {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    state: watch::Receiver<LinkState>,
+}
+
+impl Link {
+    /// Spawns a task that keeps the link connected, writes the outgoing messages to it and puts
+    /// the messages read from it into `incoming`. Every change of the link state is put into
+    /// `changes`, under `pid`.
+    fn spawn(
+        pid: u64,
+        mut connector: Connector,
+        incoming: Arc<Mutex<Vec<Message>>>,
+        changes: Arc<Mutex<Vec<(u64, LinkState)>>>,
+    ) -> Self {
+        let (outgoing, mut outgoing_rx) = mpsc::channel::<Vec<u8>>(OUTGOING_BUFFER);
+        let (state_tx, state) = watch::channel(LinkState::Reconnecting);
+        tokio::spawn(async move {
+            let run = async {
+                let mut replacement = None;
+                loop {
+                    let connection = match replacement.take() {
+                        Some(connection) => connection,
+                        None => connector.connect().await,
+                    };
+                    // messages that were waiting for the previous connection are stale by now
+                    while outgoing_rx.try_recv().is_ok() {}
+                    println!("Connected to {}", connector.addr());
+                    let _ = state_tx.send(LinkState::Connected);
+                    changes.lock().await.push((pid, LinkState::Connected));
+                    let dialer = connection.dialer;
+                    let (reader, mut writer) = connection.stream.into_split();
+                    let reading = read_messages(reader, incoming.clone());
+                    tokio::pin!(reading);
+                    loop {
+                        tokio::select! {
+                            _ = &mut reading => break,
+                            data = outgoing_rx.recv() => match data {
+                                Some(data) => {
+                                    if writer.write_all(&data).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                // the network was dropped
+                                None => return,
+                            },
+                            connection = connector.replacement(dialer) => {
+                                replacement = Some(connection);
+                                break;
+                            }
+                        }
+                    }
+                    if replacement.is_none() {
+                        println!("Lost connection to {}, reconnecting", connector.addr());
+                        let _ = state_tx.send(LinkState::Reconnecting);
+                        changes.lock().await.push((pid, LinkState::Reconnecting));
+                        // a peer that still holds on to a stale connection closes ours right away
+                        sleep(MIN_BACKOFF).await;
+                    }
+                }
+            };
+            // the link is dropped once the peer is removed from the cluster
+            tokio::select! {
+                _ = run => {}
+                _ = state_tx.closed() => {}
+            }
+        });
+        Self { outgoing, state }
+    }
+}
+
+/// Serializes a message into a frame to be written to a connection: the id of the codec, the
+/// length of the message as a big-endian `u32`, and the message. Returns `None` if the message is
+/// longer than [`MAX_FRAME_LEN`], as the receiver would close the connection on it.
+fn encode(msg: &Message, codec: Codec) -> Option<Vec<u8>> {
+    let data = match codec {
+        Codec::Bincode => bincode::serialize(msg).expect("could not serialize msg"),
+        Codec::Json => serde_json::to_vec(msg).expect("could not serialize msg"),
+    };
+    let len = match u32::try_from(data.len()) {
+        Ok(len) if len <= MAX_FRAME_LEN => len,
+        _ => {
+            println!(
+                "Dropping a message of {} bytes, too long for a frame",
+                data.len()
+            );
+            return None;
+        }
+    };
+    let mut frame = Vec::with_capacity(data.len() + 5);
+    frame.push(codec.id());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&data);
+    Some(frame)
+}
+
+fn decode(codec: Codec, data: &[u8]) -> Result<Message, String> {
+    match codec {
+        Codec::Bincode => bincode::deserialize(data).map_err(|e| e.to_string()),
+        Codec::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+    }
+}
+
+/// Reads the messages of a connection one by one.
+struct MessageReader {
+    reader: BufReader<tcp::OwnedReadHalf>,
+    data: Vec<u8>,
+}
+
+impl MessageReader {
+    fn new(reader: tcp::OwnedReadHalf) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            data: Vec::new(),
+        }
+    }
+
+    /// Returns the next message, or `None` once the connection is closed or fails. Messages that
+    /// cannot be deserialized are dropped.
+    async fn next(&mut self) -> Option<Message> {
+        loop {
+            // fails at the end of the stream
+            let codec = self.reader.read_u8().await.ok()?;
+            let len = self.reader.read_u32().await.ok()?;
+            if len > MAX_FRAME_LEN {
+                println!("Closing a connection that sent a frame of {} bytes", len);
+                return None;
+            }
+            self.data.resize(len as usize, 0);
+            self.reader.read_exact(&mut self.data).await.ok()?;
+            let msg = match Codec::from_id(codec) {
+                Some(codec) => decode(codec, &self.data),
+                None => Err(format!("unknown codec {}", codec)),
+            };
+            match msg {
+                Ok(msg) => return Some(msg),
+                Err(e) => println!("Dropping a message that could not be deserialized: {}", e),
+            }
+        }
+    }
+}
+
+/// Reads messages from a connection until it is closed or fails.
+async fn read_messages(reader: tcp::OwnedReadHalf, incoming: Arc<Mutex<Vec<Message>>>) {
+    let mut reader = MessageReader::new(reader);
+    while let Some(msg) = reader.next().await {
+        incoming.lock().await.push(msg);
+    }
+}
+
+/// The connections of the clients, when the server hosts the client API itself.
+#[derive(Default)]
+struct Clients {
+    next_id: u64,
+    /// Where the responses on each connection are written, by connection id.
+    connections: HashMap<u64, mpsc::Sender<Vec<u8>>>,
+    /// The connection on which each request that has not been answered yet was read, by client
+    /// and request id. A retry on another connection is answered on that one instead.
+    routes: HashMap<(ClientId, RequestId), u64>,
+}
+
+/// Accepts client connections and serves each one until it is closed. A client may send requests
+/// without waiting for the responses to the previous ones, which are written back as the requests
+/// complete and so possibly out of order.
+async fn accept_clients(
+    listener: TcpListener,
+    clients: Arc<Mutex<Clients>>,
+    incoming: Arc<Mutex<Vec<Message>>>,
+) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                println!("Failed to accept a client: {}", e);
+                continue;
+            }
+        };
+        let clients = clients.clone();
+        let incoming = incoming.clone();
+        tokio::spawn(async move {
+            if !matches!(
+                timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream)).await,
+                Ok(Ok(()))
+            ) {
+                return;
+            }
+            let (outgoing, mut outgoing_rx) = mpsc::channel::<Vec<u8>>(OUTGOING_BUFFER);
+            let id = {
+                let mut clients = clients.lock().await;
+                let id = clients.next_id;
+                clients.next_id += 1;
+                clients.connections.insert(id, outgoing);
+                id
+            };
+            let (reader, mut writer) = stream.into_split();
+            tokio::spawn(async move {
+                while let Some(data) = outgoing_rx.recv().await {
+                    if writer.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let mut reader = MessageReader::new(reader);
+            while let Some(msg) = reader.next().await {
+                match msg {
+                    Message::APIRequest(request) => {
+                        let route = (request.client_id, request.request_id);
+                        clients.lock().await.routes.insert(route, id);
+                        incoming.lock().await.push(Message::APIRequest(request));
+                    }
+                    _ => println!("Dropping a message from a client that is not a request"),
+                }
+            }
+            // closes the connection once the responses queued for it are written
+            let mut clients = clients.lock().await;
+            clients.connections.remove(&id);
+            clients.routes.retain(|_, connection| *connection != id);
+        });
+    }
 }
 
 pub struct Network {
-    sockets: HashMap<u64, tcp::OwnedWriteHalf>,
-    api_socket: Option<tcp::OwnedWriteHalf>,
+    pid: u64,
+    /// How the messages that this server sends are encoded.
+    codec: Codec,
+    /// The links to the peers, and to the client API of the network actor under pid 0 unless
+    /// `clients` is set.
+    links: HashMap<u64, Link>,
+    /// Set if the server hosts the client API itself.
+    clients: Option<Arc<Mutex<Clients>>>,
+    /// The addresses of the peers, which are connected to directly if `acceptors` is set.
+    peer_addrs: HashMap<u64, String>,
+    acceptors: Option<Acceptors>,
     incoming_msg_buf: Arc<Mutex<Vec<Message>>>,
+    link_changes: Arc<Mutex<Vec<(u64, LinkState)>>>,
 }
 
 impl Network {
-    fn get_my_api_addr() -> String {
-        format!("net:800{}", *MY_PID)
+    fn get_my_api_addr(&self) -> String {
+        format!("net:800{}", self.pid)
     }
 
-    fn get_peer_addr(receiver_pid: u64) -> String {
-        format!("net:80{}{}", *MY_PID, receiver_pid)
+    fn get_peer_addr(&self, receiver_pid: u64) -> String {
+        format!("net:80{}{}", self.pid, receiver_pid)
     }
 
     /// Sends the message to the receiver.
     /// u64 0 is the Client.
+    ///
+    /// The message is dropped if the receiver is not connected, which OmniPaxos recovers from
+    /// like from any other lost message. A client gets a lost response by sending the request
+    /// again with the same id, which is answered with the result it originally had.
     pub(crate) async fn send(&mut self, receiver: u64, msg: Message) {
-        let writer = if receiver == 0 {
-            self.api_socket.as_mut()
-        } else {
-            self.sockets.get_mut(&receiver)
-        };
-        if let Some(writer) = writer {
-            let mut data = serde_json::to_vec(&msg).expect("could not serialize msg");
-            data.push(b'\n');
-            writer.write_all(&data).await.unwrap();
+        if !self.is_connected(receiver) {
+            return;
+        }
+        if let (Some(link), Some(frame)) = (self.links.get(&receiver), encode(&msg, self.codec)) {
+            let _ = link.outgoing.try_send(frame);
+        }
+    }
+
+    /// Sends the response to the connection on which the request was read. Like other messages,
+    /// it is dropped if that connection is closed.
+    pub(crate) async fn reply(&mut self, client_id: ClientId, response: APIResponse) {
+        let route = (client_id, response.request_id());
+        let msg = Message::APIResponse(response);
+        match &self.clients {
+            Some(clients) => {
+                let mut clients = clients.lock().await;
+                let connection = clients
+                    .routes
+                    .remove(&route)
+                    .and_then(|id| clients.connections.get(&id));
+                if let (Some(connection), Some(frame)) = (connection, encode(&msg, self.codec)) {
+                    let _ = connection.try_send(frame);
+                }
+            }
+            // the network actor passes the response on to the client
+            None => self.send(0, msg).await,
+        }
+    }
+
+    /// Returns true if the connection to the receiver is up.
+    pub(crate) fn is_connected(&self, receiver: u64) -> bool {
+        match self.links.get(&receiver) {
+            Some(link) => *link.state.borrow() == LinkState::Connected,
+            None => false,
         }
     }
 
@@ -56,65 +547,438 @@ impl Network {
         ret
     }
 
-    /// Constructs a new Network instance and connects the Sockets.
-    pub(crate) async fn new() -> Self {
-        let peers: Vec<u64> = NODES
-            .iter()
-            .filter(|pid| **pid != *MY_PID)
-            .cloned()
-            .collect();
-        let mut peer_addrs = HashMap::new();
-        for pid in &peers {
-            peer_addrs.insert(*pid, Self::get_peer_addr(*pid));
-        }
-        let api_stream = TcpStream::connect(Self::get_my_api_addr()).await.unwrap();
-        let (api_reader, api_writer) = api_stream.into_split();
-        let api_socket = Some(api_writer);
-        let incoming_msg_buf = Arc::new(Mutex::new(vec![]));
-        let msg_buf = incoming_msg_buf.clone();
-        tokio::spawn(async move {
-            let mut reader = BufReader::new(api_reader);
-            let mut data = Vec::new();
-            loop {
-                data.clear();
-                let bytes_read = reader.read_until(b'\n', &mut data).await;
-                if bytes_read.is_err() {
-                    // stream ended?
-                    panic!("stream ended?")
-                }
-                let msg: Message =
-                    serde_json::from_slice(&data).expect("could not deserialize msg");
-                msg_buf.lock().await.push(msg);
-            }
-        });
+    /// Returns the links whose state changed since last called, with their new state in the
+    /// order of the changes.
+    pub(crate) async fn get_link_changes(&mut self) -> Vec<(u64, LinkState)> {
+        std::mem::take(&mut *self.link_changes.lock().await)
+    }
 
-        let mut sockets = HashMap::new();
-        for peer in &peers {
-            let addr = peer_addrs.get(&peer).unwrap().clone();
-            let stream = TcpStream::connect(addr).await.unwrap();
-            let (reader, writer) = stream.into_split();
-            sockets.insert(*peer, writer);
-            let msg_buf = incoming_msg_buf.clone();
-            tokio::spawn(async move {
-                let mut reader = BufReader::new(reader);
-                let mut data = Vec::new();
-                loop {
-                    data.clear();
-                    let bytes_read = reader.read_until(b'\n', &mut data).await;
-                    if bytes_read.is_err() {
-                        // stream ended?
-                        panic!("stream ended?")
+    /// Disconnects from a peer that was removed from the cluster.
+    pub(crate) async fn remove_peer(&mut self, pid: u64) {
+        self.links.remove(&pid);
+        if let Some(acceptors) = &self.acceptors {
+            acceptors.lock().await.remove(&pid);
+        }
+    }
+
+    /// Connects to a peer that was added to the cluster.
+    pub(crate) async fn add_peer(&mut self, pid: u64) {
+        if self.links.contains_key(&pid) {
+            return;
+        }
+        let connector = match &self.acceptors {
+            Some(acceptors) => {
+                let addr = match self.peer_addrs.get(&pid) {
+                    Some(addr) => addr.clone(),
+                    None => {
+                        println!("Cannot connect to peer {}, its address is unknown", pid);
+                        return;
                     }
-                    let msg: Message =
-                        serde_json::from_slice(&data).expect("could not deserialize msg");
-                    msg_buf.lock().await.push(msg);
+                };
+                let (acceptor, accepted) = mpsc::channel(1);
+                acceptors.lock().await.insert(pid, acceptor);
+                Connector::Direct {
+                    addr,
+                    my_pid: self.pid,
+                    accepted,
                 }
-            });
+            }
+            None => Connector::Proxy {
+                addr: self.get_peer_addr(pid),
+            },
+        };
+        let link = Link::spawn(
+            pid,
+            connector,
+            self.incoming_msg_buf.clone(),
+            self.link_changes.clone(),
+        );
+        self.links.insert(pid, link);
+    }
+
+    /// Constructs a new Network instance, which connects the sockets to the client API and to the
+    /// other `nodes` in the background. The peers and clients are connected through the network
+    /// actor, unless the config has addresses to listen on for them.
+    pub(crate) async fn new(config: &Config, nodes: &[u64]) -> io::Result<Self> {
+        let acceptors = match &config.listen_addr {
+            Some(listen_addr) => {
+                let listener = TcpListener::bind(listen_addr).await?;
+                let acceptors = Acceptors::default();
+                tokio::spawn(accept_peers(listener, acceptors.clone()));
+                Some(acceptors)
+            }
+            None => None,
+        };
+        let incoming_msg_buf = Arc::new(Mutex::new(vec![]));
+        let clients = match &config.api_addr {
+            Some(api_addr) => {
+                let listener = TcpListener::bind(api_addr).await?;
+                let clients = Arc::new(Mutex::new(Clients::default()));
+                let accepting = accept_clients(listener, clients.clone(), incoming_msg_buf.clone());
+                tokio::spawn(accepting);
+                Some(clients)
+            }
+            None => None,
+        };
+        let mut network = Self {
+            pid: config.pid,
+            codec: config.codec,
+            links: HashMap::new(),
+            clients,
+            peer_addrs: config
+                .peers
+                .iter()
+                .map(|peer| (peer.pid, peer.addr.clone()))
+                .collect(),
+            acceptors,
+            incoming_msg_buf,
+            link_changes: Arc::new(Mutex::new(vec![])),
+        };
+        if network.clients.is_none() {
+            let api = Connector::Proxy {
+                addr: network.get_my_api_addr(),
+            };
+            let api = Link::spawn(
+                0,
+                api,
+                network.incoming_msg_buf.clone(),
+                network.link_changes.clone(),
+            );
+            network.links.insert(0, api);
         }
-        Self {
-            sockets,
-            api_socket,
+        for peer in nodes.iter().filter(|pid| **pid != config.pid) {
+            network.add_peer(*peer).await;
+        }
+        Ok(network)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Peer;
+    use crate::kv::{KVCommand, DEFAULT_NAMESPACE};
+    use crate::server::ReadConsistency;
+
+    /// Returns both ends of a local connection.
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (dialed.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Reads the messages of a connection on which `data` was written before it was closed.
+    async fn read(data: &[u8]) -> Vec<Message> {
+        let (mut dialed, accepted) = connection().await;
+        dialed.write_all(data).await.unwrap();
+        drop(dialed);
+        let mut reader = MessageReader::new(accepted.into_split().0);
+        let mut messages = vec![];
+        while let Some(msg) = reader.next().await {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    fn lease_ack() -> Message {
+        Message::LeaseAck {
+            from: 1,
+            round: 2,
+            decided_idx: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_frames_of_either_codec() {
+        let mut data = encode(&lease_ack(), Codec::Bincode).unwrap();
+        data.extend(encode(&lease_ack(), Codec::Json).unwrap());
+        let messages = read(&data).await;
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|msg| matches!(
+            msg,
+            Message::LeaseAck {
+                from: 1,
+                round: 2,
+                decided_idx: 3
+            }
+        )));
+    }
+
+    #[tokio::test]
+    async fn empty_stream() {
+        assert!(read(&[]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_header() {
+        let frame = encode(&lease_ack(), Codec::Bincode).unwrap();
+        assert!(read(&frame[..3]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_message() {
+        let frame = encode(&lease_ack(), Codec::Bincode).unwrap();
+        assert!(read(&frame[..frame.len() - 1]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn frame_longer_than_the_maximum() {
+        let mut frame = vec![Codec::Bincode.id()];
+        frame.extend_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+        assert!(read(&frame).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn undecodable_messages_are_skipped() {
+        let mut data = vec![Codec::Json.id()];
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(b"{}");
+        data.extend(encode(&lease_ack(), Codec::Bincode).unwrap());
+        assert_eq!(read(&data).await.len(), 1);
+    }
+
+    /// Accepts a connection dialed by a link and answers its handshake, like the network actor.
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_u8(PROTOCOL_VERSION).await.unwrap();
+        stream.read_u8().await.unwrap();
+        stream
+    }
+
+    /// Waits for the next changes of the links of `network`.
+    async fn link_changes(network: &mut Network) -> Vec<(u64, LinkState)> {
+        loop {
+            let changes = network.get_link_changes().await;
+            if !changes.is_empty() {
+                return changes;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// A network of server 1 with a link to server 2 through the network actor at `listener`.
+    fn proxy_network(listener: &TcpListener) -> Network {
+        let mut network = Network {
+            pid: 1,
+            codec: Codec::default(),
+            links: HashMap::new(),
+            clients: None,
+            peer_addrs: HashMap::new(),
+            acceptors: None,
+            incoming_msg_buf: Arc::new(Mutex::new(vec![])),
+            link_changes: Arc::new(Mutex::new(vec![])),
+        };
+        let connector = Connector::Proxy {
+            addr: listener.local_addr().unwrap().to_string(),
+        };
+        let link = Link::spawn(
+            2,
+            connector,
+            network.incoming_msg_buf.clone(),
+            network.link_changes.clone(),
+        );
+        network.links.insert(2, link);
+        network
+    }
+
+    #[tokio::test]
+    async fn link_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut network = proxy_network(&listener);
+        assert!(!network.is_connected(2));
+
+        let stream = accept(&listener).await;
+        let changes = link_changes(&mut network).await;
+        assert_eq!(changes, vec![(2, LinkState::Connected)]);
+        assert!(network.is_connected(2));
+
+        drop(stream);
+        let changes = link_changes(&mut network).await;
+        assert_eq!(changes, vec![(2, LinkState::Reconnecting)]);
+        assert!(!network.is_connected(2));
+
+        let stream = accept(&listener).await;
+        let changes = link_changes(&mut network).await;
+        assert_eq!(changes, vec![(2, LinkState::Connected)]);
+        assert!(network.is_connected(2));
+
+        // messages go through the new connection
+        network.send(2, lease_ack()).await;
+        let mut reader = MessageReader::new(stream.into_split().0);
+        assert!(matches!(
+            reader.next().await,
+            Some(Message::LeaseAck { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn removed_peer_is_not_reconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut network = proxy_network(&listener);
+        let stream = accept(&listener).await;
+        link_changes(&mut network).await;
+
+        network.remove_peer(2).await;
+        assert!(!network.is_connected(2));
+        // the connection is closed, and not dialed again
+        let mut reader = MessageReader::new(stream.into_split().0);
+        assert!(reader.next().await.is_none());
+        let accepted = timeout(MIN_BACKOFF * 3, listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    /// Waits for the next messages received by `network`.
+    async fn received(network: &mut Network) -> Vec<Message> {
+        loop {
+            let messages = network.get_received().await;
+            if !messages.is_empty() {
+                return messages;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// A network of server `pid` that connects to the other server of `peers` directly.
+    async fn direct_network(pid: u64, peers: &[Peer]) -> Network {
+        let config = Config {
+            pid,
+            nodes: vec![1, 2],
+            listen_addr: peers
+                .iter()
+                .find(|peer| peer.pid == pid)
+                .map(|peer| peer.addr.clone()),
+            peers: peers.to_vec(),
+            api_addr: Some("127.0.0.1:0".to_string()),
+            ..Config::default()
+        };
+        Network::new(&config, &config.nodes).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn simultaneous_dials_end_on_one_connection() {
+        let mut peers = vec![];
+        for pid in [1, 2] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            peers.push(Peer { pid, addr });
+        }
+        // both links dial as soon as the runtime gets to them, after both are listening
+        let (mut one, mut two) = tokio::join!(direct_network(1, &peers), direct_network(2, &peers));
+
+        // Each side keeps the connection dialed by server 1, and drops the other one. Were they to
+        // keep different ones, both would be closed and dialed again.
+        let mut all_changes = vec![];
+        let mut settled = false;
+        for _ in 0..20 {
+            sleep(MIN_BACKOFF * 3).await;
+            let changes = [one.get_link_changes().await, two.get_link_changes().await].concat();
+            settled = changes.is_empty() && one.is_connected(2) && two.is_connected(1);
+            if settled {
+                break;
+            }
+            all_changes.extend(changes);
+        }
+        assert!(settled);
+        assert!(all_changes
+            .iter()
+            .all(|(_, state)| *state == LinkState::Connected));
+        one.send(2, lease_ack()).await;
+        two.send(1, lease_ack()).await;
+        assert_eq!(received(&mut one).await.len(), 1);
+        assert_eq!(received(&mut two).await.len(), 1);
+        sleep(MIN_BACKOFF * 3).await;
+        assert!(one.get_link_changes().await.is_empty());
+        assert!(two.get_link_changes().await.is_empty());
+    }
+
+    /// A network of server 1 that serves the clients connecting to `listener` itself.
+    fn client_network(listener: TcpListener) -> Network {
+        let incoming_msg_buf = Arc::new(Mutex::new(vec![]));
+        let clients = Arc::new(Mutex::new(Clients::default()));
+        tokio::spawn(accept_clients(
+            listener,
+            clients.clone(),
+            incoming_msg_buf.clone(),
+        ));
+        Network {
+            pid: 1,
+            codec: Codec::default(),
+            links: HashMap::new(),
+            clients: Some(clients),
+            peer_addrs: HashMap::new(),
+            acceptors: None,
             incoming_msg_buf,
+            link_changes: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Connects to the client API at `addr` like a client, and writes the requests with the given
+    /// ids to the connection at once.
+    async fn send_requests(
+        addr: std::net::SocketAddr,
+        request_ids: &[RequestId],
+    ) -> (MessageReader, tcp::OwnedWriteHalf) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_u8(PROTOCOL_VERSION).await.unwrap();
+        stream.read_u8().await.unwrap();
+        let mut data = vec![];
+        for request_id in request_ids {
+            let request = APIRequest {
+                client_id: 7,
+                request_id: *request_id,
+                namespace: DEFAULT_NAMESPACE.to_string(),
+                kv_cmd: KVCommand::Get("k".into()),
+                consistency: ReadConsistency::Local,
+            };
+            data.extend(encode(&Message::APIRequest(request), Codec::Json).unwrap());
+        }
+        stream.write_all(&data).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        (MessageReader::new(reader), writer)
+    }
+
+    /// Waits until `network` received `count` messages.
+    async fn receive(network: &mut Network, count: usize) {
+        let mut messages = vec![];
+        while messages.len() < count {
+            messages.extend(received(network).await);
+        }
+        assert_eq!(messages.len(), count);
+    }
+
+    fn committed(request_id: RequestId) -> APIResponse {
+        APIResponse::Committed {
+            request_id,
+            log_idx: request_id,
         }
     }
+
+    async fn next_response(reader: &mut MessageReader) -> Option<RequestId> {
+        match timeout(MIN_BACKOFF, reader.next()).await {
+            Ok(Some(Message::APIResponse(response))) => Some(response.request_id()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_routed_to_the_connection_of_the_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut network = client_network(listener);
+        // two pipelined requests, the first of which is then retried on another connection
+        let (mut first, _first) = send_requests(addr, &[1, 2]).await;
+        receive(&mut network, 2).await;
+        let (mut second, _second) = send_requests(addr, &[1]).await;
+        receive(&mut network, 1).await;
+
+        network.reply(7, committed(2)).await;
+        network.reply(7, committed(1)).await;
+        assert_eq!(next_response(&mut first).await, Some(2));
+        assert_eq!(next_response(&mut second).await, Some(1));
+        assert_eq!(next_response(&mut first).await, None);
+        // answered once
+        network.reply(7, committed(1)).await;
+        assert_eq!(next_response(&mut second).await, None);
+    }
 }
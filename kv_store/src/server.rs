@@ -1,18 +1,136 @@
-use std::time::Duration;
-use crate::database::Database;
-use crate::kv::KVCommand;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::Config;
+use crate::database::{Database, DatabaseError};
+use crate::kv::{
+    ClientId, Command, CommandResult, Configuration, KVCommand, KVSnapshot, Key, KeyValue,
+    Namespace, RequestId, Value, VersionedValue, DEFAULT_NAMESPACE, SERVER_CLIENT_ID,
+};
+use crate::lease::{LeaderProgress, Lease, LeaseGrant};
 use crate::{
-    network::{Message, Network},
+    build_omni_paxos,
+    network::{LinkState, Message, Network},
     OmniPaxosKV,
 };
-use omnipaxos::util::LogEntry;
+use omnipaxos::ballot_leader_election::Ballot;
+use omnipaxos::messages::Message as OPMessage;
+use omnipaxos::storage::{Snapshot, StopSign};
+use omnipaxos::util::{LogEntry, SnapshottedEntry};
+use omnipaxos::ClusterConfig;
 use serde::{Deserialize, Serialize};
 use tokio::time;
 
+/// How often a replica that joins the cluster checks that the state it fetches still arrives, and
+/// asks the next replica for it if it does not.
+const STATE_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How up to date the result of a read must be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadConsistency {
+    /// Served from the local database of the server that receives the request. Fast, but a
+    /// partitioned or lagging server returns stale values.
+    Local,
+    /// Ordered through the OmniPaxos log and answered once decided, so the read reflects every
+    /// write that completed before it was issued.
+    Linearizable,
+    /// Served from the local database of the leader while it holds its lease, and ordered
+    /// through the log if it does not.
+    Lease,
+    /// Served from the local database of any server that is at most this far behind the leader,
+    /// rejected otherwise.
+    BoundedStaleness(Staleness),
+}
+
+/// How far behind the leader a server may be to serve a `Get`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Staleness {
+    /// Number of decided log entries the server has not applied yet.
+    Entries(u64),
+    Millis(u64),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct APIRequest {
+    pub client_id: ClientId,
+    /// Assigned by the client and echoed in the response to this request. A request that is
+    /// retried keeps its id so that it is applied at most once.
+    pub request_id: RequestId,
+    /// The namespace of the keys that `kv_cmd` addresses, the default one if not given.
+    #[serde(default)]
+    pub namespace: Namespace,
+    pub kv_cmd: KVCommand,
+    /// Only used by reads.
+    pub consistency: ReadConsistency,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum APIResponse {
-    Decided(u64),
-    Get(String, Option<String>),
+    /// The command was decided at `log_idx` and applied to the database. A written key is now at
+    /// version `log_idx`.
+    Committed { request_id: RequestId, log_idx: u64 },
+    /// `decided_idx` is the index of the log up to which the read reflects all writes, and
+    /// `version` the index of the log entry that last wrote the key.
+    Get {
+        request_id: RequestId,
+        key: Key,
+        value: Option<Value>,
+        version: Option<u64>,
+        decided_idx: u64,
+    },
+    /// `next` is the key to continue from if the range holds more keys than requested.
+    Scan {
+        request_id: RequestId,
+        entries: Vec<KeyValue>,
+        next: Option<Key>,
+        decided_idx: u64,
+    },
+    /// `current` is the value of the key after the compare-and-swap, which is the value it
+    /// was compared against if `swapped` is false.
+    CompareAndSwap {
+        request_id: RequestId,
+        log_idx: u64,
+        swapped: bool,
+        current: Option<Value>,
+    },
+    /// The transaction committed if `failed_guard` is `None`, and is otherwise the index of the
+    /// first guard that did not hold.
+    Txn { request_id: RequestId, log_idx: u64, failed_guard: Option<usize> },
+    /// `value` is the value of the key after an `Increment` or `Append`.
+    Updated { request_id: RequestId, log_idx: u64, value: Value },
+    /// A `Put` or `Delete` with `if_version` was not applied because the key is at `version`, or
+    /// absent if `None`.
+    VersionMismatch { request_id: RequestId, version: Option<u64> },
+    Failed { request_id: RequestId, reason: String },
+    /// The request has to go through the log and should be sent to the leader instead.
+    NotLeader { request_id: RequestId, leader_hint: u64 },
+}
+
+impl APIResponse {
+    pub fn request_id(&self) -> RequestId {
+        match self {
+            APIResponse::Committed { request_id, .. } => *request_id,
+            APIResponse::Get { request_id, .. } => *request_id,
+            APIResponse::Scan { request_id, .. } => *request_id,
+            APIResponse::CompareAndSwap { request_id, .. } => *request_id,
+            APIResponse::Txn { request_id, .. } => *request_id,
+            APIResponse::Updated { request_id, .. } => *request_id,
+            APIResponse::VersionMismatch { request_id, .. } => *request_id,
+            APIResponse::Failed { request_id, .. } => *request_id,
+            APIResponse::NotLeader { request_id, .. } => *request_id,
+        }
+    }
+}
+
+/// The fetch of the state that a configuration started from, one chunk after the other from one of
+/// its replicas.
+#[derive(Default)]
+pub struct StateFetch {
+    /// The replica that the state is fetched from.
+    peer: Option<u64>,
+    /// The chunks received from it so far, and their number.
+    data: Vec<u8>,
+    num_received: u64,
+    /// Whether a chunk arrived since the state requests were last checked on.
+    progressed: bool,
 }
 
 pub struct Server {
@@ -20,31 +138,394 @@ pub struct Server {
     pub network: Network,
     pub database: Database,
     pub last_decided_idx: u64,
+    pub lease: Lease,
+    /// The lease that this server granted to the leader as a follower.
+    pub lease_grant: LeaseGrant,
+    pub leader_progress: LeaderProgress,
+    /// The ballot in which this server last became the leader, and the request id of the
+    /// `Expire` that it proposed then. Once that is decided, so is everything that the previous
+    /// leaders decided.
+    pub leader_term: Option<(Ballot, RequestId)>,
+    pub configuration: Configuration,
+    /// Set while the replica joins the cluster and has no state to apply the log to yet.
+    pub awaiting_state: bool,
+    /// Set when a later configuration was started without this replica having decided the stop
+    /// sign that ended its own, with the id of that configuration and a replica in it. The state
+    /// is fetched from that replica, like when joining the cluster.
+    pub missed_configuration: Option<(u32, u64)>,
+    /// The state being fetched while joining the cluster or after missing a configuration.
+    pub state_fetch: StateFetch,
+    /// The client and request id of the `Reconfigure` request that this server proposed, answered
+    /// once its stop sign is decided.
+    pub pending_reconfiguration: Option<(ClientId, RequestId)>,
+    pub config: Config,
 }
 
 impl Server {
-    async fn process_incoming_msgs(&mut self) {
+    async fn process_incoming_msgs(&mut self) -> Result<(), DatabaseError> {
+        for (pid, state) in self.network.get_link_changes().await {
+            match state {
+                // OmniPaxos resends what the peer may have missed while the link was down
+                LinkState::Connected if self.configuration.nodes.contains(&pid) => {
+                    self.omni_paxos.reconnected(pid)
+                }
+                // Until then messages to it are dropped, like on a lossy link. A client whose
+                // response is lost gets it by sending the request again with the same id.
+                _ => {}
+            }
+        }
         let messages = self.network.get_received().await;
+        // client writes received in this tick, proposed together as one log entry
+        let mut writes = vec![];
         for msg in messages {
             match msg {
-                Message::APIRequest(kv_cmd) => {
-                    match kv_cmd {
-                        KVCommand::Get(key) => {
-                            let value = self.database.handle_command(KVCommand::Get(key.clone()));
-                            let msg = Message::APIResponse(APIResponse::Get(key, value));
-                            self.network.send(0, msg).await;
-                        },
-                        cmd => {
-                            self.omni_paxos.append(cmd).unwrap();
-                        },
+                Message::APIRequest(request) => {
+                    if let Some(cmd) = self.handle_api_request(request).await {
+                        if cmd.kv_cmd.is_batchable() {
+                            // A key is written at most once per log entry, since its version is
+                            // the index of the entry.
+                            if writes.iter().any(|write| cmd.writes_same_key(write)) {
+                                self.propose_batch(std::mem::take(&mut writes)).await;
+                            }
+                            writes.push(cmd);
+                        } else {
+                            // keep the order in which the requests arrived
+                            self.propose_batch(std::mem::take(&mut writes)).await;
+                            self.propose(cmd).await;
+                        }
+                    }
+                },
+                Message::OmniPaxosMsg(configuration_id, msg) => {
+                    // messages of another configuration are from before or after a reconfiguration
+                    if configuration_id == self.configuration.id {
+                        // no other leader is supported while the lease granted to one may be held
+                        let sender = msg.get_sender();
+                        let paxos = matches!(msg, OPMessage::SequencePaxos(_));
+                        if !paxos || self.lease_grant.allows(sender) {
+                            self.omni_paxos.handle_incoming(msg);
+                        }
+                    } else if configuration_id > self.configuration.id
+                        && !self.awaiting_state
+                        && self.missed_configuration.is_none()
+                    {
+                        let from = msg.get_sender();
+                        println!(
+                            "Configuration {} started without us, fetching its state from {}",
+                            configuration_id, from
+                        );
+                        self.missed_configuration = Some((configuration_id, from));
+                        // the state of the previous configuration is stale
+                        self.lease.revoke();
+                        self.state_fetch = StateFetch::default();
+                        self.request_state().await;
+                    }
+                },
+                Message::LeaseRequest { from, round, decided_idx } => {
+                    // only grant the lease to the leader we follow
+                    if self.omni_paxos.get_current_leader() == Some(from) {
+                        self.leader_progress.handle_report(decided_idx);
+                        self.lease_grant.grant(from);
+                        let ack = Message::LeaseAck {
+                            from: self.config.pid,
+                            round,
+                            decided_idx: self.omni_paxos.get_decided_idx(),
+                        };
+                        self.network.send(from, ack).await;
+                    }
+                },
+                Message::LeaseAck { from, round, decided_idx } => {
+                    self.lease.handle_ack(from, round, decided_idx);
+                },
+                Message::StateRequest { from, configuration_id, chunk } => {
+                    if configuration_id == self.configuration.id && !self.awaiting_state {
+                        let msg = match self.database.get_base_chunk(chunk) {
+                            Ok(Some((data, num_chunks))) => Message::StateTransfer {
+                                from: self.config.pid,
+                                configuration: self.configuration.clone(),
+                                chunk,
+                                num_chunks,
+                                data,
+                            },
+                            Ok(None) => Message::StateUnavailable {
+                                from: self.config.pid,
+                                configuration_id,
+                                reason: format!("there is no chunk {}", chunk),
+                            },
+                            Err(e) => Message::StateUnavailable {
+                                from: self.config.pid,
+                                configuration_id,
+                                reason: e.to_string(),
+                            },
+                        };
+                        self.network.send(from, msg).await;
+                    }
+                },
+                Message::StateTransfer { from, configuration, chunk, num_chunks, data } => {
+                    let missed = matches!(
+                        self.missed_configuration,
+                        Some((id, _)) if id == configuration.id
+                    );
+                    let joining = self.awaiting_state && configuration.id == self.configuration.id;
+                    let fetch = &mut self.state_fetch;
+                    if !(missed || joining)
+                        || fetch.peer != Some(from)
+                        || chunk != fetch.num_received
+                    {
+                        continue;
+                    }
+                    fetch.data.extend(data);
+                    fetch.num_received += 1;
+                    fetch.progressed = true;
+                    if fetch.num_received < num_chunks {
+                        let msg = Message::StateRequest {
+                            from: self.config.pid,
+                            configuration_id: configuration.id,
+                            chunk: fetch.num_received,
+                        };
+                        self.network.send(from, msg).await;
+                        continue;
+                    }
+                    let data = std::mem::take(&mut fetch.data);
+                    fetch.num_received = 0;
+                    let base: KVSnapshot = match serde_json::from_slice(&data) {
+                        Ok(base) => base,
+                        Err(e) => {
+                            println!("Invalid state from {}: {}", from, e);
+                            // fetched again from the next replica
+                            fetch.progressed = false;
+                            continue;
+                        }
+                    };
+                    if missed {
+                        // the instance of the previous configuration is of no use anymore
+                        self.omni_paxos = build_omni_paxos(&self.config, &configuration);
+                        self.lease.set_num_nodes(configuration.nodes.len());
+                        self.leader_progress = LeaderProgress::default();
+                        self.connect_peers(&configuration).await;
+                        self.missed_configuration = None;
+                    }
+                    self.database.apply_snapshot(base, configuration.log_offset)?;
+                    self.database.set_configuration(&configuration)?;
+                    let (id, log_offset) = (configuration.id, configuration.log_offset);
+                    println!("Joined configuration {} at log index {}", id, log_offset);
+                    // the log of the configuration is applied from its start
+                    self.last_decided_idx = 0;
+                    self.configuration = configuration;
+                    self.awaiting_state = false;
+                },
+                Message::StateUnavailable { from, configuration_id, reason } => {
+                    if self.state_fetch.peer == Some(from) {
+                        println!(
+                            "{} cannot send the state of configuration {}: {}",
+                            from, configuration_id, reason
+                        );
+                        // fetched from the next replica once the requests are checked on
+                        self.state_fetch.progressed = false;
                     }
-                }
-                Message::OmniPaxosMsg(msg) => {
-                    self.omni_paxos.handle_incoming(msg);
                 },
                 _ => unimplemented!(),
             }
         }
+        self.propose_batch(writes).await;
+        Ok(())
+    }
+
+    /// Answers the request right away if it does not have to go through the log, and returns the
+    /// command to propose otherwise.
+    async fn handle_api_request(&mut self, request: APIRequest) -> Option<Command> {
+        let APIRequest {
+            client_id,
+            request_id,
+            namespace,
+            kv_cmd,
+            consistency,
+        } = request;
+        let reserved = matches!(kv_cmd, KVCommand::Expire { .. } | KVCommand::Batch(_));
+        if client_id == SERVER_CLIENT_ID || reserved {
+            let reason = "reserved for the servers".to_string();
+            self.reply(client_id, APIResponse::Failed { request_id, reason }).await;
+            return None;
+        }
+        if kv_cmd.is_read() {
+            match self.read_locally(consistency) {
+                Ok(true) => {
+                    let decided_idx = self.configuration.log_offset + self.last_decided_idx;
+                    let response = match self.database.read(&namespace, kv_cmd) {
+                        Ok(result) => to_response(request_id, result, decided_idx),
+                        Err(e) => {
+                            let reason = format!("failed to read: {}", e);
+                            APIResponse::Failed { request_id, reason }
+                        }
+                    };
+                    self.reply(client_id, response).await;
+                    return None;
+                }
+                Ok(false) => {}
+                Err(reason) => {
+                    self.reply(client_id, APIResponse::Failed { request_id, reason }).await;
+                    return None;
+                }
+            }
+        }
+        // Redirect the client to the leader. During an election there is no leader to redirect
+        // to, and OmniPaxos holds on to the appended command until one is elected.
+        match self.omni_paxos.get_current_leader() {
+            Some(leader) if leader != self.config.pid => {
+                let response = APIResponse::NotLeader { request_id, leader_hint: leader };
+                self.reply(client_id, response).await;
+                return None;
+            }
+            _ => {}
+        }
+        if let KVCommand::Reconfigure { nodes } = kv_cmd {
+            self.reconfigure(client_id, request_id, nodes).await;
+            return None;
+        }
+        Some(Command {
+            client_id,
+            request_id,
+            coordinator_id: self.config.pid,
+            namespace,
+            kv_cmd,
+        })
+    }
+
+    /// Decides whether a read with the given consistency is served from the local database
+    /// (`Ok(true)`), ordered through the log (`Ok(false)`) or rejected.
+    fn read_locally(&mut self, consistency: ReadConsistency) -> Result<bool, String> {
+        match consistency {
+            ReadConsistency::Local => Ok(true),
+            ReadConsistency::Linearizable => Ok(false),
+            ReadConsistency::Lease => {
+                Ok(self.is_leader() && self.lease.is_valid(self.last_decided_idx))
+            }
+            ReadConsistency::BoundedStaleness(max) => {
+                let up_to_date = self.is_leader() && self.lease.is_valid(self.last_decided_idx);
+                let within = if self.is_leader() {
+                    // a leader, with or without a lease, is only behind on what it decided itself
+                    let decided_idx = self.omni_paxos.get_decided_idx();
+                    let lag = decided_idx.saturating_sub(self.last_decided_idx);
+                    match max {
+                        Staleness::Entries(max_lag) => lag <= max_lag,
+                        // what is decided is applied within a message interval
+                        Staleness::Millis(max_ms) => {
+                            lag == 0 || max_ms >= self.config.msg_interval_ms
+                        }
+                    }
+                } else {
+                    self.leader_progress.is_within(max, self.last_decided_idx)
+                };
+                if up_to_date || within {
+                    Ok(true)
+                } else {
+                    Err("too far behind the leader".to_string())
+                }
+            }
+        }
+    }
+
+    async fn propose(&mut self, cmd: Command) {
+        let requests: Vec<(ClientId, RequestId)> = cmd
+            .commands()
+            .iter()
+            .filter(|cmd| cmd.client_id != SERVER_CLIENT_ID)
+            .map(|cmd| (cmd.client_id, cmd.request_id))
+            .collect();
+        if let Err(e) = self.omni_paxos.append(cmd) {
+            let reason = format!("failed to append to the log: {:?}", e);
+            for (client_id, request_id) in requests {
+                let reason = reason.clone();
+                self.reply(client_id, APIResponse::Failed { request_id, reason }).await;
+            }
+        }
+    }
+
+    /// Proposes client writes as a single log entry, which is decided and applied in one go.
+    async fn propose_batch(&mut self, mut writes: Vec<Command>) {
+        let cmd = match writes.len() {
+            0 => return,
+            1 => writes.remove(0),
+            _ => Command {
+                client_id: SERVER_CLIENT_ID,
+                // every command of the batch is deduplicated in its own client's session
+                request_id: 0,
+                coordinator_id: self.config.pid,
+                namespace: DEFAULT_NAMESPACE.to_string(),
+                kv_cmd: KVCommand::Batch(writes),
+            },
+        };
+        self.propose(cmd).await;
+    }
+
+    /// Proposes the stop sign that ends the current configuration and starts one with `nodes`.
+    async fn reconfigure(&mut self, client_id: ClientId, request_id: RequestId, nodes: Vec<u64>) {
+        let reason = if nodes.is_empty() || nodes.contains(&0) {
+            "a configuration needs nodes, none of which can be 0".to_string()
+        } else {
+            let config = ClusterConfig {
+                configuration_id: self.configuration.id + 1,
+                nodes,
+                ..Default::default()
+            };
+            match self.omni_paxos.reconfigure(config, None) {
+                Ok(()) => {
+                    self.pending_reconfiguration = Some((client_id, request_id));
+                    return;
+                }
+                Err(e) => format!("failed to reconfigure: {:?}", e),
+            }
+        };
+        self.reply(client_id, APIResponse::Failed { request_id, reason }).await;
+    }
+
+    /// Proposes the wall-clock time of the leader to advance the replicated clock, which expires
+    /// the keys written with a TTL. Returns the request id of the proposed command.
+    async fn propose_expire(&mut self) -> RequestId {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time before the UNIX epoch")
+            .as_millis() as u64;
+        let cmd = Command {
+            client_id: SERVER_CLIENT_ID,
+            // an older timestamp is superseded, since the clock never goes backwards anyway
+            request_id: now,
+            coordinator_id: self.config.pid,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            kv_cmd: KVCommand::Expire { now },
+        };
+        self.propose(cmd).await;
+        now
+    }
+
+    fn is_leader(&self) -> bool {
+        self.omni_paxos.get_current_leader() == Some(self.config.pid)
+    }
+
+    /// Starts a new lease round if we are the leader.
+    async fn renew_lease(&mut self) {
+        if !self.is_leader() {
+            self.lease.revoke();
+            self.leader_term = None;
+            return;
+        }
+        let ballot = self.omni_paxos.get_promise();
+        if self.leader_term.map(|(term, _)| term) != Some(ballot) {
+            // newly elected, the lease waits for an entry of this term to be decided
+            self.lease.start_term();
+            let request_id = self.propose_expire().await;
+            self.leader_term = Some((ballot, request_id));
+        }
+        let decided_idx = self.omni_paxos.get_decided_idx();
+        let round = self.lease.start_round(decided_idx);
+        let peers = self.configuration.nodes.iter().filter(|pid| **pid != self.config.pid);
+        for peer in peers.copied().collect::<Vec<_>>() {
+            let msg = Message::LeaseRequest { from: self.config.pid, round, decided_idx };
+            self.network.send(peer, msg).await;
+        }
+    }
+
+    async fn reply(&mut self, client_id: ClientId, response: APIResponse) {
+        self.network.reply(client_id, response).await;
     }
 
     async fn send_outgoing_msgs(&mut self) {
@@ -52,57 +533,257 @@ impl Server {
         for msg in messages {
             let receiver = msg.get_receiver();
             self.network
-                .send(receiver, Message::OmniPaxosMsg(msg))
+                .send(receiver, Message::OmniPaxosMsg(self.configuration.id, msg))
                 .await;
         }
     }
 
-    async fn handle_decided_entries(&mut self) {
+    async fn handle_decided_entries(&mut self) -> Result<(), DatabaseError> {
+        if self.awaiting_state {
+            // the entries are applied once the state they apply to has arrived
+            return Ok(());
+        }
         let new_decided_idx = self.omni_paxos.get_decided_idx();
         if self.last_decided_idx < new_decided_idx {
             let decided_entries = self.omni_paxos.read_decided_suffix(self.last_decided_idx).unwrap();
-            self.update_database(decided_entries);
+            let stop_sign = self.update_database(decided_entries).await?;
             self.last_decided_idx = new_decided_idx;
-            /*** reply client ***/
-            let msg = Message::APIResponse(APIResponse::Decided(new_decided_idx));
-            self.network.send(0, msg).await;
-            // snapshotting
-            if new_decided_idx % 5 == 0 {
+            if let Some(stop_sign) = stop_sign {
+                return self.start_configuration(stop_sign).await;
+            }
+            // Snapshotting. A snapshot covers the log of the current configuration only, and the
+            // replicas that catch up with it apply it on top of the state the configuration
+            // started from.
+            let interval = self.config.snapshot_interval;
+            if interval != 0 && new_decided_idx % interval == 0 {
                 println!("Log before: {:?}", self.omni_paxos.read_decided_suffix(0).unwrap());
                 self.omni_paxos.snapshot(Some(new_decided_idx), true)
                     .expect("Failed to snapshot");
                 println!("Log after: {:?}\n", self.omni_paxos.read_decided_suffix(0).unwrap());
             }
         }
+        Ok(())
+    }
+
+    /// Moves on to the configuration decided by the stop sign that ended the log of the current
+    /// one. A replica that is not in the new configuration stops participating.
+    async fn start_configuration(&mut self, stop_sign: StopSign) -> Result<(), DatabaseError> {
+        let log_offset = self.configuration.log_offset + self.last_decided_idx;
+        let next = stop_sign.next_config;
+        let configuration = Configuration {
+            id: next.configuration_id,
+            nodes: next.nodes,
+            log_offset,
+        };
+        self.database.set_configuration(&configuration)?;
+        println!("Moving to configuration {}: {:?}", configuration.id, configuration.nodes);
+        // the stop sign was decided after all
+        self.missed_configuration = None;
+        if let Some((client_id, request_id)) = self.pending_reconfiguration.take() {
+            let response = APIResponse::Committed { request_id, log_idx: log_offset - 1 };
+            self.reply(client_id, response).await;
+        }
+        if configuration.nodes.contains(&self.config.pid) {
+            self.omni_paxos = build_omni_paxos(&self.config, &configuration);
+            self.last_decided_idx = 0;
+            self.lease.set_num_nodes(configuration.nodes.len());
+            self.leader_progress = LeaderProgress::default();
+            self.connect_peers(&configuration).await;
+        }
+        self.configuration = configuration;
+        Ok(())
     }
 
-    fn update_database(&self, decided_entries: Vec<LogEntry<KVCommand>>) {
+    /// Connects to the peers that `configuration` adds, and disconnects from those that it
+    /// removes. The added replicas ask for the state once they are connected.
+    async fn connect_peers(&mut self, configuration: &Configuration) {
+        for pid in &self.configuration.nodes {
+            if !configuration.nodes.contains(pid) {
+                self.network.remove_peer(*pid).await;
+            }
+        }
+        for pid in &configuration.nodes {
+            if *pid != self.config.pid {
+                self.network.add_peer(*pid).await;
+            }
+        }
+    }
+
+    /// Applies decided entries to the database, and returns the stop sign if the log ends with
+    /// one. A failed read is answered with an error, but any other error is returned, since the
+    /// replica would diverge from the others if it skipped the entry.
+    async fn update_database(
+        &mut self,
+        decided_entries: Vec<LogEntry<Command>>,
+    ) -> Result<Option<StopSign>, DatabaseError> {
+        // entries are applied at their index in the sequence of all configurations' logs
+        let mut decided_idx = self.configuration.log_offset + self.last_decided_idx;
         for entry in decided_entries {
             match entry {
                 LogEntry::Decided(cmd) => {
-                    self.database.handle_command(cmd);
+                    let is_term_entry = match self.leader_term {
+                        Some((_, request_id)) => {
+                            cmd.client_id == SERVER_CLIENT_ID
+                                && cmd.coordinator_id == self.config.pid
+                                && cmd.request_id == request_id
+                                && matches!(cmd.kv_cmd, KVCommand::Expire { .. })
+                        }
+                        None => false,
+                    };
+                    let requests: Vec<_> = cmd
+                        .commands()
+                        .iter()
+                        .map(|cmd| (cmd.client_id, cmd.request_id, cmd.coordinator_id))
+                        .collect();
+                    let is_read = cmd.kv_cmd.is_read();
+                    let results = match self.database.handle_command(cmd, decided_idx) {
+                        Ok(CommandResult::Batch(results)) => {
+                            results.into_iter().map(Ok).collect()
+                        }
+                        Ok(result) => vec![Ok(result)],
+                        // a read changes nothing, so only its client is affected
+                        Err(e) if is_read => vec![Err(e)],
+                        Err(e) => return Err(e),
+                    };
+                    decided_idx += 1;
+                    if is_term_entry {
+                        self.lease.handle_term_entry(decided_idx - self.configuration.log_offset);
+                    }
+                    /*** reply client ***/
+                    let requests = requests.into_iter().zip(results);
+                    for ((client_id, request_id, coordinator_id), result) in requests {
+                        if coordinator_id == self.config.pid && client_id != SERVER_CLIENT_ID {
+                            let response = match result {
+                                Ok(result) => to_response(request_id, result, decided_idx),
+                                Err(e) => {
+                                    let reason = format!("failed to read: {}", e);
+                                    APIResponse::Failed { request_id, reason }
+                                }
+                            };
+                            self.reply(client_id, response).await;
+                        }
+                    }
                 }
+                LogEntry::Snapshotted(SnapshottedEntry { trimmed_idx, snapshot, .. }) => {
+                    // we are behind the compacted part of the log, catch up with the snapshot
+                    let snapshot = match self.database.get_base()? {
+                        Some(mut base) => {
+                            base.merge(snapshot);
+                            base
+                        }
+                        None => snapshot,
+                    };
+                    decided_idx = self.configuration.log_offset + trimmed_idx;
+                    self.database.apply_snapshot(snapshot, decided_idx)?;
+                }
+                LogEntry::StopSign(stop_sign, true) => return Ok(Some(stop_sign)),
                 _ => {}
             }
         }
+        Ok(None)
+    }
+
+    /// Asks a replica of the configuration that this replica joins, or the one that is known to be
+    /// in the configuration that it missed, for the state that the configuration started from.
+    /// The replica is asked again from the first chunk, or the next replica is, once no chunk
+    /// arrived for a [`STATE_REQUEST_INTERVAL`].
+    async fn request_state(&mut self) {
+        let (configuration_id, peers) = match self.missed_configuration {
+            Some((configuration_id, peer)) => (configuration_id, vec![peer]),
+            None if self.awaiting_state => {
+                let pid = self.config.pid;
+                let peers = self.configuration.nodes.iter().copied().filter(|p| *p != pid);
+                (self.configuration.id, peers.collect())
+            }
+            None => return,
+        };
+        let fetch = &mut self.state_fetch;
+        if std::mem::take(&mut fetch.progressed) {
+            return;
+        }
+        let position = fetch.peer.and_then(|peer| peers.iter().position(|p| *p == peer));
+        let peer = match position {
+            Some(i) => peers[(i + 1) % peers.len()],
+            None => match peers.first() {
+                Some(peer) => *peer,
+                None => return,
+            },
+        };
+        *fetch = StateFetch { peer: Some(peer), ..StateFetch::default() };
+        let msg = Message::StateRequest { from: self.config.pid, configuration_id, chunk: 0 };
+        self.network.send(peer, msg).await;
     }
 
-    pub(crate) async fn run(&mut self) {
-        let mut msg_interval = time::interval(Duration::from_millis(1));
-        let mut tick_interval = time::interval(Duration::from_millis(10));
+    /// Runs the server until it is removed from the cluster, or until the database fails to apply
+    /// a decided entry.
+    pub(crate) async fn run(&mut self) -> Result<(), DatabaseError> {
+        let millis = Duration::from_millis;
+        let mut msg_interval = time::interval(millis(self.config.msg_interval_ms));
+        let mut tick_interval = time::interval(millis(self.config.tick_interval_ms));
+        let mut expire_interval = time::interval(millis(self.config.expire_interval_ms));
+        let mut state_request_interval = time::interval(STATE_REQUEST_INTERVAL);
         loop {
             tokio::select! {
                 biased;
                 _ = msg_interval.tick() => {
-                    self.process_incoming_msgs().await;
+                    self.process_incoming_msgs().await?;
                     self.send_outgoing_msgs().await;
-                    self.handle_decided_entries().await;
+                    self.handle_decided_entries().await?;
+                    if !self.configuration.nodes.contains(&self.config.pid) {
+                        println!("Removed from the cluster, stopping");
+                        return Ok(());
+                    }
                 },
                 _ = tick_interval.tick() => {
                     self.omni_paxos.tick();
+                    self.renew_lease().await;
+                },
+                _ = expire_interval.tick() => {
+                    if self.is_leader() {
+                        self.propose_expire().await;
+                    }
+                },
+                _ = state_request_interval.tick() => {
+                    self.request_state().await;
                 },
                 else => (),
             }
         }
     }
 }
+
+/// Builds the response to a request whose command has the given result. `decided_idx` is the
+/// index of the log up to which a read reflects all writes.
+fn to_response(request_id: RequestId, result: CommandResult, decided_idx: u64) -> APIResponse {
+    match result {
+        CommandResult::Written(log_idx) => APIResponse::Committed { request_id, log_idx },
+        CommandResult::Read(key, value) => {
+            let (value, version) = match value {
+                Some(VersionedValue { value, version, .. }) => (Some(value), Some(version)),
+                None => (None, None),
+            };
+            APIResponse::Get { request_id, key, value, version, decided_idx }
+        }
+        CommandResult::Scan { entries, next } => {
+            APIResponse::Scan { request_id, entries, next, decided_idx }
+        }
+        CommandResult::CompareAndSwap { log_idx, swapped, current } => {
+            APIResponse::CompareAndSwap { request_id, log_idx, swapped, current }
+        }
+        CommandResult::Txn { log_idx, failed_guard } => {
+            APIResponse::Txn { request_id, log_idx, failed_guard }
+        }
+        CommandResult::Updated { log_idx, value } => {
+            APIResponse::Updated { request_id, log_idx, value }
+        }
+        CommandResult::Invalid(reason) => APIResponse::Failed { request_id, reason },
+        CommandResult::VersionMismatch(version) => {
+            APIResponse::VersionMismatch { request_id, version }
+        }
+        CommandResult::Superseded => APIResponse::Failed {
+            request_id,
+            reason: "superseded by a newer request of the client".to_string(),
+        },
+        CommandResult::Batch(_) => unreachable!("batches are answered per command"),
+    }
+}
@@ -0,0 +1,124 @@
+use crate::network::Codec;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{env, fmt, fs, io};
+
+/// The keys of [`Config`], which can be overridden by environment variables and flags.
+const KEYS: &[&str] = &["port_mappings", "client_ports", "codec"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Pairs of ports whose sockets are proxied to each other.
+    pub port_mappings: Vec<[u64; 2]>,
+    /// Ports on which the nodes are supposed to connect with their client API socket.
+    pub client_ports: Vec<u64>,
+    /// How the requests to the nodes are encoded, `bincode` or `json`.
+    #[serde(default)]
+    pub codec: Codec,
+}
+
+/// Same as in KV demo
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io(String, io::Error),
+    /// The file is not valid TOML, or a value is missing or has the wrong type.
+    Parse(String),
+    /// The values are well-formed but do not work together.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path, e),
+            ConfigError::Parse(reason) => write!(f, "{}", reason),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the configuration the same way as the KV demo: from the TOML file given with
+    /// `--config` or `CONFIG`, overridden by environment variables such as `CLIENT_PORTS` and
+    /// then by flags such as `--client-ports "[8001, 8002]"`.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut path = env::var("CONFIG").ok();
+        let mut flags = vec![];
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let key = match arg.strip_prefix("--") {
+                Some(key) => key.replace('-', "_"),
+                None => return Err(ConfigError::Invalid(format!("unexpected argument {}", arg))),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::Invalid(format!("missing value for {}", arg)))?;
+            if key == "config" {
+                path = Some(value);
+            } else if KEYS.contains(&key.as_str()) {
+                flags.push((key, value));
+            } else {
+                return Err(ConfigError::Invalid(format!("unknown flag {}", arg)));
+            }
+        }
+        let mut table = match path {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(format!("{}: {}", path, e)))?
+            }
+            None => toml::value::Table::new(),
+        };
+        for key in KEYS {
+            if let Ok(value) = env::var(key.to_uppercase()) {
+                table.insert(key.to_string(), parse_value(&value));
+            }
+        }
+        for (key, value) in flags {
+            table.insert(key, parse_value(&value));
+        }
+        let config: Self = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut ports: Vec<u64> = self.port_mappings.iter().flatten().copied().collect();
+        ports.extend(&self.client_ports);
+        ports.sort_unstable();
+        if let Some(port) = ports.windows(2).find(|pair| pair[0] == pair[1]) {
+            let reason = format!("port {} is used more than once", port[0]);
+            return Err(ConfigError::Invalid(reason));
+        }
+        if self.client_ports.is_empty() {
+            return Err(ConfigError::Invalid("client_ports is empty".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Returns the port that every mapped port is proxied to, in both directions.
+    pub fn port_map(&self) -> HashMap<u64, u64> {
+        let mut map = HashMap::new();
+        for [a, b] in &self.port_mappings {
+            map.insert(*a, *b);
+            map.insert(*b, *a);
+        }
+        map
+    }
+}
+
+/// Parses an overridden value as a TOML value, or as a string if it is not one.
+fn parse_value(value: &str) -> toml::Value {
+    match toml::from_str::<toml::value::Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table
+            .remove("value")
+            .unwrap_or(toml::Value::String(value.to_string())),
+        Err(_) => toml::Value::String(value.to_string()),
+    }
+}
@@ -1,67 +1,186 @@
+use crate::config::Config;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashMap;
-use std::env;
-use tokio;
-
-#[macro_use]
-extern crate lazy_static;
 
+mod config;
 mod network;
 
-lazy_static! {
-    /// Port to port mapping, for which sockets should be proxied to each other.
-    pub static ref PORT_MAPPINGS: HashMap<u64, u64> = if let Ok(var) = env::var("PORT_MAPPINGS") {
-        let mut map = HashMap::new();
-        let x: Vec<Vec<u64>> = serde_json::from_str(&var).expect("wrong config format");
-        for mapping in x {
-            if mapping.len() != 2 {
-                panic!("wrong config format");
-            }
-            map.insert(mapping[0], mapping[1]);
-            map.insert(mapping[1], mapping[0]);
-        }
-        map
-    } else {
-        panic!("missing config")
-    };
-    /// Ports on which the nodes are supposed to connect with their client API socket.
-    pub static ref CLIENT_PORTS: Vec<u64> = if let Ok(var) = env::var("CLIENT_PORTS") {
-        serde_json::from_str(&var).expect("wrong config format")
-    } else {
-        panic!("missing config")
-    };
+/// Same as in KV demo
+pub type Key = Vec<u8>;
+pub type Value = Vec<u8>;
+pub type Namespace = String;
+
+/// Same as in KV demo
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compaction {
+    #[default]
+    Level,
+    Universal,
+    Fifo,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KeyValue {
-    pub key: String,
-    pub value: String,
+    pub key: Key,
+    pub value: Value,
 }
 
 /// Same as in KV demo
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum KVCommand {
+pub enum Guard {
+    Equals(KeyValue),
+    Absent(Key),
+    VersionEquals { key: Key, version: u64 },
+}
+
+/// Same as in KV demo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TxnOp {
     Put(KeyValue),
-    Delete(String),
-    Get(String),
+    Delete(Key),
+}
+
+/// Same as in KV demo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KVCommand {
+    Put {
+        key: Key,
+        value: Value,
+        if_version: Option<u64>,
+        ttl_ms: Option<u64>,
+    },
+    Delete {
+        key: Key,
+        if_version: Option<u64>,
+    },
+    Get(Key),
+    CompareAndSwap {
+        key: Key,
+        expected: Option<Value>,
+        new: Option<Value>,
+    },
+    Txn { guards: Vec<Guard>, ops: Vec<TxnOp> },
+    Increment { key: Key, delta: i64 },
+    Append { key: Key, suffix: Value },
+    Scan {
+        start: Key,
+        end: Option<Key>,
+        limit: usize,
+    },
+    Prefix {
+        prefix: Key,
+        start: Option<Key>,
+        limit: usize,
+    },
+    Expire { now: u64 },
+    CreateNamespace {
+        name: Namespace,
+        compaction: Compaction,
+    },
+    DropNamespace(Namespace),
+    Batch(Vec<Command>),
+    Reconfigure { nodes: Vec<u64> },
+}
+
+pub type ClientId = u64;
+pub type RequestId = u64;
+
+/// Same as in KV demo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Command {
+    pub client_id: ClientId,
+    pub request_id: RequestId,
+    pub coordinator_id: u64,
+    pub namespace: Namespace,
+    pub kv_cmd: KVCommand,
+}
+
+/// Same as in KV demo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadConsistency {
+    Local,
+    Linearizable,
+    Lease,
+    BoundedStaleness(Staleness),
+}
+
+/// Same as in KV demo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Staleness {
+    Entries(u64),
+    Millis(u64),
+}
+
+/// Same as in KV demo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct APIRequest {
+    pub client_id: ClientId,
+    pub request_id: RequestId,
+    pub namespace: Namespace,
+    pub kv_cmd: KVCommand,
+    pub consistency: ReadConsistency,
 }
 
 /// Same as in KV demo
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum APIResponse {
-    Decided(u64),
-    Get(String, Option<String>),
+    Committed { request_id: RequestId, log_idx: u64 },
+    Get {
+        request_id: RequestId,
+        key: Key,
+        value: Option<Value>,
+        version: Option<u64>,
+        decided_idx: u64,
+    },
+    Scan {
+        request_id: RequestId,
+        entries: Vec<KeyValue>,
+        next: Option<Key>,
+        decided_idx: u64,
+    },
+    CompareAndSwap {
+        request_id: RequestId,
+        log_idx: u64,
+        swapped: bool,
+        current: Option<Value>,
+    },
+    Txn { request_id: RequestId, log_idx: u64, failed_guard: Option<usize> },
+    Updated { request_id: RequestId, log_idx: u64, value: Value },
+    VersionMismatch { request_id: RequestId, version: Option<u64> },
+    Failed { request_id: RequestId, reason: String },
+    NotLeader { request_id: RequestId, leader_hint: u64 },
+}
+
+impl APIResponse {
+    pub fn request_id(&self) -> RequestId {
+        match self {
+            APIResponse::Committed { request_id, .. } => *request_id,
+            APIResponse::Get { request_id, .. } => *request_id,
+            APIResponse::Scan { request_id, .. } => *request_id,
+            APIResponse::CompareAndSwap { request_id, .. } => *request_id,
+            APIResponse::Txn { request_id, .. } => *request_id,
+            APIResponse::Updated { request_id, .. } => *request_id,
+            APIResponse::VersionMismatch { request_id, .. } => *request_id,
+            APIResponse::Failed { request_id, .. } => *request_id,
+            APIResponse::NotLeader { request_id, .. } => *request_id,
+        }
+    }
 }
 
+/// Same as in KV demo, up to the messages of the client API. The binary codec encodes a variant
+/// by its position, so the messages between the nodes keep theirs without being readable here.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum Message {
-    APIRequest(KVCommand),
+    OmniPaxosMsg(()),
+    APIRequest(APIRequest),
     APIResponse(APIResponse),
 }
 
 #[tokio::main]
 async fn main() {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    });
     // TODO: setup dashboard
-    network::run().await;
+    network::run(config).await;
 }
@@ -1,55 +1,269 @@
-use serde_json;
 use std::collections::HashMap;
 use std::{
     fmt,
     io::{stdout, Write},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpListener,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp, TcpListener, TcpStream},
     sync::{broadcast, mpsc, Mutex},
-    time::sleep,
+    time::{sleep, timeout},
 };
 
-use crate::{KVCommand, KeyValue, Message, CLIENT_PORTS, PORT_MAPPINGS};
+use crate::config::Config;
+use crate::{
+    APIRequest, APIResponse, ClientId, Compaction, Guard, KVCommand, KeyValue, Message,
+    ReadConsistency, RequestId, Staleness, TxnOp,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+
+/// How many times a request is redirected to another node before giving up on it.
+const MAX_REDIRECTS: usize = 3;
+/// How long a node that connects has to send its protocol version.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Same as in KV demo
+const PROTOCOL_VERSION: u8 = 1;
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Same as in KV demo. Frames between nodes are forwarded as they are, whatever their codec.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Bincode,
+    Json,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Bincode => 1,
+            Codec::Json => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Bincode),
+            2 => Some(Codec::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Exchanges protocol versions with a node that connected, same as in KV demo.
+async fn handshake(stream: &mut TcpStream) -> bool {
+    let exchange = async {
+        stream.write_u8(PROTOCOL_VERSION).await?;
+        stream.read_u8().await
+    };
+    match timeout(HANDSHAKE_TIMEOUT, exchange).await {
+        Ok(Ok(version)) if version >= PROTOCOL_VERSION => true,
+        Ok(Ok(version)) => {
+            println!(
+                "Closing a connection: unsupported protocol version {}",
+                version
+            );
+            false
+        }
+        _ => false,
+    }
+}
 
-pub async fn run() {
+/// Same as in KV demo
+fn encode(msg: &Message, codec: Codec) -> Option<Vec<u8>> {
+    let data = match codec {
+        Codec::Bincode => bincode::serialize(msg).expect("could not serialize msg"),
+        Codec::Json => serde_json::to_vec(msg).expect("could not serialize msg"),
+    };
+    let len = match u32::try_from(data.len()) {
+        Ok(len) if len <= MAX_FRAME_LEN => len,
+        _ => {
+            println!(
+                "Dropping a message of {} bytes, too long for a frame",
+                data.len()
+            );
+            return None;
+        }
+    };
+    let mut frame = Vec::with_capacity(data.len() + 5);
+    frame.push(codec.id());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&data);
+    Some(frame)
+}
+
+/// Decodes the message in a frame read by [`read_frame`].
+fn decode(frame: &[u8]) -> Result<Message, String> {
+    let data = &frame[5..];
+    match Codec::from_id(frame[0]) {
+        Some(Codec::Bincode) => bincode::deserialize(data).map_err(|e| e.to_string()),
+        Some(Codec::Json) => serde_json::from_slice(data).map_err(|e| e.to_string()),
+        None => Err(format!("unknown codec {}", frame[0])),
+    }
+}
+
+/// Reads the next frame, including its header, or returns `None` once the connection is closed or
+/// fails.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Option<Vec<u8>> {
+    let mut frame = vec![0; 5];
+    reader.read_exact(&mut frame).await.ok()?;
+    let len = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+    if len > MAX_FRAME_LEN {
+        println!("Closing a connection that sent a frame of {} bytes", len);
+        return None;
+    }
+    frame.resize(5 + len as usize, 0);
+    reader.read_exact(&mut frame[5..]).await.ok()?;
+    Some(frame)
+}
+
+/// A request that has not been answered yet.
+struct PendingRequest {
+    request: APIRequest,
+    sent: Instant,
+    redirects: usize,
+}
+
+/// Nodes connect their client API socket on port 800{PID}.
+fn api_port(pid: u64) -> u64 {
+    8000 + pid
+}
+
+/// Sends a request to the node connected on `port`, and returns whether it could. A socket that
+/// cannot be written to is dropped, and the node is reachable again once it reconnects.
+async fn send_request(
+    api_sockets: &mut HashMap<u64, tcp::OwnedWriteHalf>,
+    port: u64,
+    request: &APIRequest,
+    codec: Codec,
+) -> bool {
+    let writer = match api_sockets.get_mut(&port) {
+        Some(writer) => writer,
+        None => return false,
+    };
+    let cmd = Message::APIRequest(request.clone());
+    let frame = match encode(&cmd, codec) {
+        Some(frame) => frame,
+        None => return false,
+    };
+    match writer.write_all(&frame).await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Couldn't send request {} to {}: {}", request.request_id, port, e);
+            api_sockets.remove(&port);
+            false
+        }
+    }
+}
+
+pub async fn run(config: Config) {
+    let codec = config.codec;
+    let port_mappings = Arc::new(config.port_map());
     // setup client sockets to talk to nodes
     let api_sockets = Arc::new(Mutex::new(HashMap::new()));
-    for port in CLIENT_PORTS.iter() {
+    let pending: Arc<Mutex<HashMap<RequestId, PendingRequest>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    // port of the last known leader, requests without an explicit port are sent there
+    let leader_port: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+    for port in config.client_ports.iter().copied() {
         let api_sockets = api_sockets.clone();
+        let pending = pending.clone();
+        let leader_port = leader_port.clone();
         tokio::spawn(async move {
             let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
                 .await
                 .unwrap();
-            let (socket, _addr) = listener.accept().await.unwrap();
-            let (reader, writer) = socket.into_split();
-            api_sockets.lock().await.insert(port, writer);
-            // receiver actor
-            tokio::spawn(async move {
-                let mut reader = BufReader::new(reader);
-                loop {
-                    let mut data = vec![];
-                    let bytes_read = reader.read_until(b'\n', &mut data).await.unwrap();
-                    if bytes_read == 0 {
-                        // dropped socket EOF
-                        println!("{port} disconnected");
-                        api_sockets.lock().await.remove(port);
-                        break;
-                    }
-                    if let Ok(msg) = serde_json::from_slice::<Message>(&data) {
-                        println!("From {}: {:?}", port, msg); // TODO: handle APIResponse
-                    }
+            // a node that lost its connection connects again
+            loop {
+                let (mut socket, _addr) = listener.accept().await.unwrap();
+                if !handshake(&mut socket).await {
+                    continue;
                 }
-            });
+                let (reader, writer) = socket.into_split();
+                api_sockets.lock().await.insert(port, writer);
+                let api_sockets = api_sockets.clone();
+                let pending = pending.clone();
+                let leader_port = leader_port.clone();
+                // receiver actor
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(reader);
+                    loop {
+                        let frame = match read_frame(&mut reader).await {
+                            Some(frame) => frame,
+                            None => {
+                                // dropped socket EOF
+                                println!("{port} disconnected");
+                                api_sockets.lock().await.remove(&port);
+                                break;
+                            }
+                        };
+                        if let Ok(msg) = decode(&frame) {
+                            match msg {
+                                Message::APIResponse(APIResponse::NotLeader {
+                                    request_id,
+                                    leader_hint,
+                                }) => {
+                                    let leader = api_port(leader_hint);
+                                    *leader_port.lock().await = Some(leader);
+                                    // Resend to the leader with the same request id, so that it is
+                                    // applied at most once.
+                                    let request = match pending.lock().await.get_mut(&request_id) {
+                                        Some(p) if p.redirects < MAX_REDIRECTS => {
+                                            p.redirects += 1;
+                                            Some(p.request.clone())
+                                        }
+                                        _ => None,
+                                    };
+                                    let sent = match request {
+                                        Some(request) => {
+                                            let mut api = api_sockets.lock().await;
+                                            send_request(&mut api, leader, &request, codec).await
+                                        }
+                                        None => false,
+                                    };
+                                    if !sent {
+                                        pending.lock().await.remove(&request_id);
+                                        println!(
+                                            "From {}: request {} has no leader",
+                                            port, request_id
+                                        );
+                                    }
+                                }
+                                Message::APIResponse(response) => {
+                                    let sent = pending.lock().await.remove(&response.request_id());
+                                    match sent {
+                                        Some(p) => println!(
+                                            "From {}: {:?} ({:?})",
+                                            port,
+                                            response,
+                                            p.sent.elapsed()
+                                        ),
+                                        None => println!("From {}: {:?}", port, response),
+                                    }
+                                }
+                                msg => println!("From {}: {:?}", port, msg),
+                            }
+                        }
+                    }
+                });
+            }
         });
     }
 
     // Handle user input to propose values
     let api = api_sockets.clone();
+    let client_ports = config.client_ports.clone();
     tokio::spawn(async move {
+        // a fresh session every time the client starts
+        let client_id: ClientId = rand::random();
+        let mut next_request_id: RequestId = 0;
+        // the namespace that commands address, changed with `use`
+        let mut namespace = String::new();
         loop {
             // Get input
             let mut input = String::new();
@@ -61,31 +275,48 @@ pub async fn run() {
                 .await
                 .expect("Did not enter a string");
 
+            let mut words = input.split_whitespace();
+            if words.next() == Some("use") {
+                // without a name, back to the default namespace
+                namespace = words.next().unwrap_or_default().to_string();
+                println!("Using namespace {:?}", namespace);
+                continue;
+            }
+
             // Parse and send command
             match parse_command(input) {
-                Ok((command, None)) => {
-                    let mut sent_command = false;
-                    for port in CLIENT_PORTS.iter() {
-                        if let Some(writer) = api.lock().await.get_mut(port) {
-                            let cmd = Message::APIRequest(command.clone());
-                            let mut data =
-                                serde_json::to_vec(&cmd).expect("could not serialize cmd");
-                            data.push(b'\n');
-                            writer.write_all(&data).await.unwrap();
-                            sent_command = true;
-                            break;
+                Ok((kv_cmd, consistency, port)) => {
+                    let request_id = next_request_id;
+                    next_request_id += 1;
+                    let request = APIRequest {
+                        client_id,
+                        request_id,
+                        namespace: namespace.clone(),
+                        kv_cmd,
+                        consistency,
+                    };
+                    let leader = *leader_port.lock().await;
+                    let mut api = api.lock().await;
+                    let port = port.or_else(|| {
+                        leader
+                            .iter()
+                            .chain(client_ports.iter())
+                            .find(|port| api.contains_key(port))
+                            .copied()
+                    });
+                    match port {
+                        Some(port) if api.contains_key(&port) => {
+                            let p = PendingRequest {
+                                request: request.clone(),
+                                sent: Instant::now(),
+                                redirects: 0,
+                            };
+                            pending.lock().await.insert(request_id, p);
+                            if !send_request(&mut api, port, &request, codec).await {
+                                pending.lock().await.remove(&request_id);
+                            }
                         }
-                    }
-                    if !sent_command {
-                        println!("Couldn't send command, no node is reachable");
-                    }
-                }
-                Ok((command, Some(port))) => {
-                    if let Some(writer) = api.lock().await.get_mut(&port) {
-                        let cmd = Message::APIRequest(command.clone());
-                        let mut data = serde_json::to_vec(&cmd).expect("could not serialize cmd");
-                        data.push(b'\n');
-                        writer.write_all(&data).await.unwrap();
+                        _ => println!("Couldn't send command, no node is reachable"),
                     }
                 }
                 Err(err) => println!("{err}"),
@@ -98,7 +329,7 @@ pub async fn run() {
     // setup intra-cluster communication
     let partitions: Arc<Mutex<Vec<(u64, u64, f32)>>> = Arc::new(Mutex::new(vec![]));
     let mut out_channels = HashMap::new();
-    for port in PORT_MAPPINGS.keys() {
+    for port in port_mappings.keys() {
         let (sender, _rec) = broadcast::channel::<Vec<u8>>(10000);
         let sender = Arc::new(sender);
         out_channels.insert(*port, sender.clone());
@@ -108,39 +339,45 @@ pub async fn run() {
     let (central_sender, mut central_receiver) = mpsc::channel(10000);
     let central_sender = Arc::new(central_sender);
 
-    for port in PORT_MAPPINGS.keys() {
+    for port in port_mappings.keys().copied() {
         let out_chans = out_channels.clone();
+        let port_mappings = port_mappings.clone();
         let central_sender = central_sender.clone();
         tokio::spawn(async move {
             let central_sender = central_sender.clone();
             let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
                 .await
                 .unwrap();
-            let (socket, _addr) = listener.accept().await.unwrap();
-            let (reader, mut writer) = socket.into_split();
-            // sender actor
-            let out_channels = out_chans.clone();
-            tokio::spawn(async move {
-                let mut receiver = out_channels.get(port).unwrap().clone().subscribe();
-                while let Ok(data) = receiver.recv().await {
-                    let _ = writer.write_all(&data).await;
-                }
-            });
-            // receiver actor
-            let central_sender = central_sender.clone();
-            tokio::spawn(async move {
-                let mut reader = BufReader::new(reader);
-                loop {
-                    let mut data = vec![];
-                    reader.read_until(b'\n', &mut data).await.unwrap();
-                    if let Err(_e) = central_sender
-                        .send((port, PORT_MAPPINGS.get(port).unwrap(), data))
-                        .await
-                    {
-                        break;
-                    };
+            // a node that lost its connection connects again
+            loop {
+                let (mut socket, _addr) = listener.accept().await.unwrap();
+                if !handshake(&mut socket).await {
+                    continue;
                 }
-            });
+                let (reader, mut writer) = socket.into_split();
+                // sender actor
+                let out_channels = out_chans.clone();
+                tokio::spawn(async move {
+                    let mut receiver = out_channels.get(&port).unwrap().clone().subscribe();
+                    while let Ok(data) = receiver.recv().await {
+                        if writer.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                // receiver actor
+                let central_sender = central_sender.clone();
+                let to_port = port_mappings[&port];
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(reader);
+                    // dropped socket EOF
+                    while let Some(frame) = read_frame(&mut reader).await {
+                        if let Err(_e) = central_sender.send((port, to_port, frame)).await {
+                            break;
+                        };
+                    }
+                });
+            }
         });
     }
 
@@ -148,11 +385,11 @@ pub async fn run() {
     while let Some((from_port, to_port, msg)) = central_receiver.recv().await {
         // drop message if network is partitioned between sender and receiver
         for (from, to, _probability) in partitions.lock().await.iter() {
-            if from == from_port && to == to_port {
+            if *from == from_port && *to == to_port {
                 continue;
             }
         }
-        let sender = out_channels.get(to_port).unwrap().clone();
+        let sender = out_channels.get(&to_port).unwrap().clone();
         let _ = sender.send(msg);
     }
 }
@@ -163,7 +400,9 @@ impl fmt::Display for ParseCommandError {
         write!(f, "{}", self.0)
     }
 }
-fn parse_command(line: String) -> Result<(KVCommand, Option<u64>), ParseCommandError> {
+fn parse_command(
+    line: String,
+) -> Result<(KVCommand, ReadConsistency, Option<u64>), ParseCommandError> {
     let mut words = line.trim().split(" ");
     let command_type = words
         .next()
@@ -171,37 +410,291 @@ fn parse_command(line: String) -> Result<(KVCommand, Option<u64>), ParseCommandE
 
     let command = match command_type {
         "delete" => {
-            let value = words
-                .next()
-                .ok_or(ParseCommandError("Not enough arguments".to_string()))?;
-            let port = words.next().map(|x| x.parse::<u64>().unwrap());
-            (KVCommand::Delete(value.to_string()), port.into())
+            let (key, if_version) = parse_versioned_key(words.next())?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Delete { key, if_version },
+                ReadConsistency::Local,
+                port,
+            )
         }
-        "get" => {
-            let value = words
+        "get" | "lget" | "leaseget" => {
+            let key = parse_bytes(words.next())?;
+            let port = parse_port(words.next())?;
+            let consistency = match command_type {
+                "lget" => ReadConsistency::Linearizable,
+                "leaseget" => ReadConsistency::Lease,
+                _ => ReadConsistency::Local,
+            };
+            (KVCommand::Get(key), consistency, port)
+        }
+        "sget" => {
+            let key = parse_bytes(words.next())?;
+            let max = words
                 .next()
                 .ok_or(ParseCommandError("Not enough arguments".to_string()))?;
-            let port = words.next().map(|x| x.parse::<u64>().unwrap());
-            (KVCommand::Get(value.to_string()), port.into())
+            let staleness = match max.strip_suffix("ms") {
+                Some(ms) => ms.parse().map(Staleness::Millis),
+                None => max.parse().map(Staleness::Entries),
+            }
+            .map_err(|_| ParseCommandError("Invalid staleness".to_string()))?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Get(key),
+                ReadConsistency::BoundedStaleness(staleness),
+                port,
+            )
         }
         "put" => {
-            let key = words
+            let (key, if_version) = parse_versioned_key(words.next())?;
+            let value = parse_bytes(words.next())?;
+            let mut next = words.next();
+            let ttl_ms = match next.and_then(|x| x.strip_prefix("ttl=")) {
+                Some(ttl) => {
+                    next = words.next();
+                    let ttl = ttl
+                        .parse()
+                        .map_err(|_| ParseCommandError("Invalid TTL".to_string()))?;
+                    Some(ttl)
+                }
+                None => None,
+            };
+            let port = parse_port(next)?;
+            (
+                KVCommand::Put {
+                    key,
+                    value,
+                    if_version,
+                    ttl_ms,
+                },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "cas" => {
+            let key = parse_bytes(words.next())?;
+            // "-" stands for an absent key
+            let expected = parse_optional_bytes(words.next())?;
+            let new = parse_optional_bytes(words.next())?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::CompareAndSwap { key, expected, new },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "incr" => {
+            let key = parse_bytes(words.next())?;
+            let delta = words
+                .next()
+                .ok_or(ParseCommandError("Not enough arguments".to_string()))?
+                .parse()
+                .map_err(|_| ParseCommandError("Invalid delta".to_string()))?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Increment { key, delta },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "append" => {
+            let key = parse_bytes(words.next())?;
+            let suffix = parse_bytes(words.next())?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Append { key, suffix },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "scan" => {
+            let start = parse_bytes(words.next())?;
+            // "-" scans to the last key
+            let end = parse_optional_bytes(words.next())?;
+            let limit = parse_limit(words.next())?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Scan { start, end, limit },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "prefix" => {
+            let prefix = parse_bytes(words.next())?;
+            let limit = parse_limit(words.next())?;
+            // "-" starts at the first key with the prefix
+            let start = parse_optional_bytes(words.next())?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Prefix {
+                    prefix,
+                    start,
+                    limit,
+                },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "txn" => (parse_txn(words)?, ReadConsistency::Local, None),
+        "create" => {
+            let name = words
                 .next()
                 .ok_or(ParseCommandError("Not enough arguments".to_string()))?
                 .to_string();
-            let value = words
+            let mut next = words.next();
+            let compaction = match next {
+                Some("level") => Some(Compaction::Level),
+                Some("universal") => Some(Compaction::Universal),
+                Some("fifo") => Some(Compaction::Fifo),
+                _ => None,
+            };
+            if compaction.is_some() {
+                next = words.next();
+            }
+            let port = parse_port(next)?;
+            (
+                KVCommand::CreateNamespace {
+                    name,
+                    compaction: compaction.unwrap_or_default(),
+                },
+                ReadConsistency::Local,
+                port,
+            )
+        }
+        "drop" => {
+            let name = words
                 .next()
                 .ok_or(ParseCommandError("Not enough arguments".to_string()))?
                 .to_string();
-            let port = words.next().map(|x| x.parse::<u64>().unwrap());
-            (KVCommand::Put(KeyValue { key, value }), port.into())
+            let port = parse_port(words.next())?;
+            (KVCommand::DropNamespace(name), ReadConsistency::Local, port)
+        }
+        "reconfigure" => {
+            let nodes = words
+                .next()
+                .ok_or(ParseCommandError("Not enough arguments".to_string()))?
+                .split(',')
+                .map(|pid| pid.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ParseCommandError("PIDs must be u64".to_string()))?;
+            let port = parse_port(words.next())?;
+            (
+                KVCommand::Reconfigure { nodes },
+                ReadConsistency::Local,
+                port,
+            )
         }
         "help" => {
             return Err(ParseCommandError(
-                "Commands: put <key> <value>, get <key>, delete <key> (optional <port>)".into(),
+                "Commands: put <key>[@<version>] <value> [ttl=<ms>], get <key>, lget <key>, leaseget <key>, \
+                 sget <key> <max lag e.g. 3 or 100ms>, cas <key> <expected|-> <new|->, \
+                 incr <key> <delta>, append <key> <suffix>, scan <start> <end|-> <limit>, prefix <prefix> <limit> <start|->, \
+                 delete <key>[@<version>], create <namespace> [level|universal|fifo], \
+                 drop <namespace>, reconfigure <pid>,<pid>,... (optional <port>), use [<namespace>], \
+                 txn [if <key>=<value|-> | if <key>@<version>]... \
+                 then [put <key> <value> | delete <key>]..."
+                    .into(),
             ));
         }
         _ => Err(ParseCommandError("Invalid command type".to_string()))?,
     };
     Ok(command)
 }
+
+/// Parses a key or value, given in hex as `0x<hex>`, in base64 as `b64:<base64>` or as text.
+fn parse_bytes(word: Option<&str>) -> Result<Vec<u8>, ParseCommandError> {
+    let word = word.ok_or(ParseCommandError("Not enough arguments".to_string()))?;
+    if let Some(hex) = word.strip_prefix("0x") {
+        hex::decode(hex).map_err(|_| ParseCommandError("Invalid hex".to_string()))
+    } else if let Some(b64) = word.strip_prefix("b64:") {
+        STANDARD
+            .decode(b64)
+            .map_err(|_| ParseCommandError("Invalid base64".to_string()))
+    } else {
+        Ok(word.as_bytes().to_vec())
+    }
+}
+
+/// Like [`parse_bytes`], where "-" stands for no key or value.
+fn parse_optional_bytes(word: Option<&str>) -> Result<Option<Vec<u8>>, ParseCommandError> {
+    match word {
+        Some("-") => Ok(None),
+        word => parse_bytes(word).map(Some),
+    }
+}
+
+/// Splits `key@version` into the key and the version that a write is conditional on.
+fn parse_versioned_key(word: Option<&str>) -> Result<(Vec<u8>, Option<u64>), ParseCommandError> {
+    if let Some((key, version)) = word.and_then(|word| word.rsplit_once('@')) {
+        if let Ok(version) = version.parse() {
+            return Ok((parse_bytes(Some(key))?, Some(version)));
+        }
+    }
+    Ok((parse_bytes(word)?, None))
+}
+
+/// Parses the optional port of the server that a command is sent to.
+fn parse_port(port: Option<&str>) -> Result<Option<u64>, ParseCommandError> {
+    match port {
+        Some(port) => match port.parse() {
+            Ok(port) => Ok(Some(port)),
+            Err(_) => Err(ParseCommandError("Invalid port".to_string())),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_limit(limit: Option<&str>) -> Result<usize, ParseCommandError> {
+    match limit
+        .ok_or(ParseCommandError("Not enough arguments".to_string()))?
+        .parse()
+    {
+        Ok(0) => Err(ParseCommandError("Limit must be positive".to_string())),
+        Ok(limit) => Ok(limit),
+        Err(_) => Err(ParseCommandError("Invalid limit".to_string())),
+    }
+}
+
+/// Parses the guards and operations of a `txn` command, which takes no port.
+fn parse_txn<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<KVCommand, ParseCommandError> {
+    let not_enough = || ParseCommandError("Not enough arguments".to_string());
+    let mut guards = vec![];
+    let mut ops = vec![];
+    while let Some(word) = words.next() {
+        match word {
+            "if" if ops.is_empty() => {
+                let guard = words.next().ok_or_else(not_enough)?;
+                let guard = if let Some((key, value)) = guard.split_once('=') {
+                    let key = parse_bytes(Some(key))?;
+                    match parse_optional_bytes(Some(value))? {
+                        Some(value) => Guard::Equals(KeyValue { key, value }),
+                        None => Guard::Absent(key),
+                    }
+                } else if let Some((key, version)) = guard.split_once('@') {
+                    let version = version
+                        .parse()
+                        .map_err(|_| ParseCommandError("Invalid version".to_string()))?;
+                    Guard::VersionEquals {
+                        key: parse_bytes(Some(key))?,
+                        version,
+                    }
+                } else {
+                    return Err(ParseCommandError("Invalid guard".to_string()));
+                };
+                guards.push(guard);
+            }
+            "then" => {}
+            "put" => {
+                let key = parse_bytes(words.next())?;
+                let value = parse_bytes(words.next())?;
+                ops.push(TxnOp::Put(KeyValue { key, value }));
+            }
+            "delete" => {
+                let key = parse_bytes(words.next())?;
+                ops.push(TxnOp::Delete(key));
+            }
+            _ => return Err(ParseCommandError("Invalid transaction".to_string())),
+        }
+    }
+    Ok(KVCommand::Txn { guards, ops })
+}